@@ -0,0 +1,156 @@
+use crate::{
+    bit_reader::PacketBitReader,
+    floor::FloorError,
+    mdct::Mdct,
+    mode::Mode,
+    residue::ResidueError,
+    util,
+    vorbis::{IdHeader, SetupHeader},
+    window::Window,
+};
+use bitstream_io::BitRead;
+use thiserror::Error;
+
+/// A decoded audio packet: one windowed block of time domain samples per
+/// channel, ready to be overlapped with the neighboring blocks.
+#[derive(Debug)]
+pub struct AudioPacket {
+    channels: Vec<Vec<f32>>,
+}
+
+impl AudioPacket {
+    /// Decode an audio packet. `mdct` holds the inverse MDCT for the short
+    /// and the long blocksize, in that order, and `window` windows the
+    /// resulting blocks.
+    pub fn decode(
+        reader: &mut PacketBitReader,
+        id_header: &IdHeader,
+        setup_header: &SetupHeader,
+        mdct: &[Mdct; 2],
+        window: &Window,
+    ) -> Result<Self, AudioError> {
+        // Mode and window shape. The mapping, submap, floor and residue
+        // numbers were all checked when the setup header was parsed.
+        let mode = read_mode(reader, setup_header)?;
+        let mapping = &setup_header.mappings()[mode.mapping() as usize];
+        let blockflag = mode.blockflag();
+        let (previous_window_flag, next_window_flag) = if blockflag == true {
+            (reader.read_bit()?, reader.read_bit()?)
+        } else {
+            (false, false)
+        };
+        let blocksize_0 = id_header.blocksize_0() as usize;
+        let n = if blockflag == true {
+            id_header.blocksize_1() as usize
+        } else {
+            blocksize_0
+        };
+        let audio_channels = setup_header.audio_channels() as usize;
+
+        // Floor curves
+        let codebooks = setup_header.codebooks();
+        let mut floors: Vec<Option<Vec<f32>>> = Vec::with_capacity(audio_channels);
+        for &submap_number in mapping.mux() {
+            let submap = &mapping.submaps()[submap_number as usize];
+            let floor = &setup_header.floors()[submap.floor() as usize];
+            floors.push(floor.decode_packet(reader, codebooks, n / 2)?);
+        }
+
+        // Channels with an unused floor aren't decoded, unless they're coupled
+        // with a channel that is used
+        let mut no_residue: Vec<bool> = floors.iter().map(|f| f.is_none()).collect();
+        for (magnitude, angle) in mapping.coupling_pairs() {
+            if no_residue[magnitude] == false || no_residue[angle] == false {
+                no_residue[magnitude] = false;
+                no_residue[angle] = false;
+            }
+        }
+
+        // Residue vectors, decoded together for all channels in each submap
+        let mut residues: Vec<Vec<f32>> = vec![Vec::new(); audio_channels];
+        for (i, submap) in mapping.submaps().iter().enumerate() {
+            let submap_channels: Vec<usize> = (0..audio_channels)
+                .filter(|&j| mapping.mux()[j] as usize == i)
+                .collect();
+            let do_not_decode: Vec<bool> = submap_channels.iter().map(|&j| no_residue[j]).collect();
+            let residue = &setup_header.residues()[submap.residue() as usize];
+            let vectors = residue.decode_packet(reader, codebooks, n / 2, &do_not_decode)?;
+            for (j, vector) in submap_channels.into_iter().zip(vectors) {
+                residues[j] = vector;
+            }
+        }
+
+        mapping.inverse_couple(&mut residues);
+
+        // Dot product of floor and residue, then back to the time domain
+        let mdct = &mdct[blockflag as usize];
+        let channels = floors
+            .iter()
+            .zip(residues.iter())
+            .map(|(floor, residue)| {
+                let spectrum: Vec<f32> = match floor {
+                    Some(floor) => floor.iter().zip(residue).map(|(f, r)| f * r).collect(),
+                    None => vec![0.0; n / 2],
+                };
+                let mut samples = mdct.inverse(&spectrum);
+                window.apply(
+                    &mut samples,
+                    blockflag,
+                    previous_window_flag,
+                    next_window_flag,
+                );
+                samples
+            })
+            .collect();
+
+        Ok(Self { channels })
+    }
+
+    /// Read just enough of `packet` to tell whether it holds a long block,
+    /// without decoding it.
+    pub fn read_blockflag(packet: &[u8], setup_header: &SetupHeader) -> Result<bool, AudioError> {
+        let mut reader = PacketBitReader::new(packet);
+        Ok(read_mode(&mut reader, setup_header)?.blockflag())
+    }
+
+    /// Windowed time domain samples for each channel, one block long.
+    pub fn channels(&self) -> &[Vec<f32>] {
+        &self.channels
+    }
+}
+
+/// Read the packet type and the mode number at the start of an audio packet.
+fn read_mode<'a>(
+    reader: &mut PacketBitReader,
+    setup_header: &'a SetupHeader,
+) -> Result<&'a Mode, AudioError> {
+    let packet_type: bool = reader.read_bit()?;
+    if packet_type == true {
+        return Err(AudioError::NotAudioPacket);
+    }
+
+    let modes = setup_header.modes();
+    let mode_number: u8 = reader.read(util::ilog(modes.len() as i32 - 1))?;
+    modes
+        .get(mode_number as usize)
+        .ok_or(AudioError::InvalidModeNumber(mode_number))
+}
+
+#[derive(Debug, Error)]
+pub enum AudioError {
+    #[error("Not an audio packet")]
+    NotAudioPacket,
+
+    #[error("Invalid mode number: {0}")]
+    InvalidModeNumber(u8),
+
+    #[error(transparent)]
+    Floor(#[from] FloorError),
+
+    #[error(transparent)]
+    Residue(#[from] ResidueError),
+
+    // Represents all cases of `std::io::Error`.
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+}
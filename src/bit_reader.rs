@@ -0,0 +1,162 @@
+use bitstream_io::{BitRead, Endianness, LittleEndian, Numeric, SignedNumeric};
+use std::io;
+
+/// Reads bits from a whole packet held in memory, least significant bit
+/// first like `BitReader` with `LittleEndian`. Unlike `BitReader` it can also
+/// look at the bits that come next without reading them, which lets Huffman
+/// codewords be decoded with a table lookup.
+pub struct PacketBitReader<'a> {
+    data: &'a [u8],
+    position: usize, // In bits
+}
+
+impl<'a> PacketBitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    /// Return the next `bits` bits (at most 32) without reading them, with
+    /// the first bit as the least significant. Bits past the end of the
+    /// packet are zero.
+    pub fn peek(&self, bits: u32) -> u32 {
+        debug_assert!(bits <= 32);
+        let byte = self.position / 8;
+        let shift = self.position % 8;
+        let word = match self.data.get(byte..byte + 8) {
+            Some(bytes) => {
+                let mut word = [0; 8];
+                word.copy_from_slice(bytes);
+                u64::from_le_bytes(word)
+            }
+            None => self
+                .data
+                .iter()
+                .skip(byte)
+                .enumerate()
+                .fold(0, |word, (i, &b)| word | (b as u64) << (8 * i)),
+        };
+        let mask = (1 << bits) - 1;
+        ((word >> shift) & mask) as u32
+    }
+
+    /// Move past `bits` bits. It's an error to go past the end of the packet,
+    /// in which case everything has been read.
+    pub fn consume(&mut self, bits: u32) -> io::Result<()> {
+        let position = self.position + bits as usize;
+        if position > self.data.len() * 8 {
+            self.position = self.data.len() * 8;
+            Err(io::ErrorKind::UnexpectedEof.into())
+        } else {
+            self.position = position;
+            Ok(())
+        }
+    }
+}
+
+impl BitRead for PacketBitReader<'_> {
+    fn read_bit(&mut self) -> io::Result<bool> {
+        let bit = self.peek(1);
+        self.consume(1)?;
+        Ok(bit == 1)
+    }
+
+    fn read<U>(&mut self, bits: u32) -> io::Result<U>
+    where
+        U: Numeric,
+    {
+        if bits > U::bits_size() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "excessive bits for type read",
+            ));
+        }
+
+        // A byte at a time, since that's what every `Numeric` can be made from
+        let mut value = U::default();
+        let mut done = 0;
+        while done < bits {
+            let chunk = (bits - done).min(8);
+            let byte = U::from_u8(self.peek(chunk) as u8);
+            self.consume(chunk)?;
+            value |= byte << done;
+            done += chunk;
+        }
+        Ok(value)
+    }
+
+    fn read_signed<S>(&mut self, bits: u32) -> io::Result<S>
+    where
+        S: SignedNumeric,
+    {
+        LittleEndian::read_signed(self, bits)
+    }
+
+    fn skip(&mut self, bits: u32) -> io::Result<()> {
+        self.consume(bits)
+    }
+
+    fn byte_aligned(&self) -> bool {
+        self.position.is_multiple_of(8)
+    }
+
+    fn byte_align(&mut self) {
+        self.position = (self.position + 7) / 8 * 8;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitstream_io::BitReader;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_peek() {
+        let input = [
+            0b10110100, 0b00000001, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x0F,
+        ];
+        let mut reader = PacketBitReader::new(&input);
+        assert_eq!(reader.peek(3), 0b100);
+        assert_eq!(reader.peek(3), 0b100);
+        reader.consume(2).unwrap();
+        assert_eq!(reader.peek(8), 0b01101101);
+        assert_eq!(reader.peek(32), 0xFFFF_C06D);
+
+        // Past the end of the packet is zero
+        reader.consume(60).unwrap();
+        assert_eq!(reader.peek(8), 0b111111);
+        reader.consume(10).unwrap();
+        assert_eq!(reader.peek(32), 0);
+        let err = reader.consume(1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_matches_bit_reader() {
+        let input: Vec<u8> = (0..64).map(|i| (i * 37 + 11) as u8).collect();
+        let mut cursor = Cursor::new(&input);
+        let mut expected = BitReader::endian(&mut cursor, LittleEndian);
+        let mut reader = PacketBitReader::new(&input);
+        for bits in [1, 7, 13, 0, 32, 3, 8, 24, 5, 31, 2, 9, 16, 1, 4] {
+            let value: u32 = reader.read(bits).unwrap();
+            assert_eq!(value, expected.read::<u32>(bits).unwrap());
+        }
+        for bits in [3, 8, 17, 1, 32] {
+            let value: i32 = reader.read_signed(bits).unwrap();
+            assert_eq!(value, expected.read_signed::<i32>(bits).unwrap());
+        }
+        let value: u64 = reader.read(40).unwrap();
+        assert_eq!(value, expected.read::<u64>(40).unwrap());
+        assert_eq!(reader.read_bit().unwrap(), expected.read_bit().unwrap());
+        reader.byte_align();
+        expected.byte_align();
+        assert_eq!(
+            reader.read::<u8>(8).unwrap(),
+            expected.read::<u8>(8).unwrap()
+        );
+
+        assert!(reader.read::<u8>(9).is_err());
+        assert!(reader.skip(512).is_err());
+        assert!(reader.read_bit().is_err());
+    }
+}
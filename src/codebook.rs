@@ -1,7 +1,14 @@
-use crate::{huffman::HuffmanTree, util};
+use crate::{
+    bit_reader::PacketBitReader,
+    huffman::{HuffmanError, HuffmanTree},
+    util::{self, UnexpectedEof},
+};
 use bitstream_io::{BitRead, BitReader};
 use thiserror::Error;
 
+/// The most values a codebook's vector lookup table may unpack to
+const MAX_VECTORS_LEN: u64 = 1 << 24;
+
 #[derive(Debug, Default, PartialEq)]
 pub struct Codebook {
     dimensions: u16,
@@ -54,8 +61,11 @@ impl Codebook {
             // The codeword list is encoded in ascending length order. Rather than reading a length for every
             // codeword, we read the number of codewords per length.
             let mut current_entry: u32 = 0;
-            let mut current_length = reader.read::<u8>(1)? + 1;
+            let mut current_length = reader.read::<u8>(5)? + 1;
             while current_entry < entries {
+                if current_length > 32 {
+                    return Err(CodebookError::CodewordTooLong(current_length));
+                }
                 let bits_to_read = util::ilog((entries - current_entry) as i32);
                 let number = reader.read::<u32>(bits_to_read)?;
                 for _ in 0..number {
@@ -76,36 +86,51 @@ impl Codebook {
             1 | 2 => {
                 let minimum_value = util::float32_unpack(reader.read(32)?);
                 let delta_value = util::float32_unpack(reader.read(32)?);
+                for value in [minimum_value, delta_value] {
+                    if value.is_finite() == false {
+                        return Err(CodebookError::ValueOutOfRange(value));
+                    }
+                }
                 let value_bits = reader.read::<u8>(4)? + 1;
                 let sequence_p: bool = reader.read_bit()?;
+
+                // Check the size before working anything out from it. A 24 bit
+                // entry count times 16 bit dimensions always fits in a u64.
+                let vectors_len = entries as u64 * dimensions as u64;
+                if vectors_len > MAX_VECTORS_LEN {
+                    return Err(CodebookError::VectorTableTooLarge(vectors_len));
+                }
                 let lookup_values = if lookup_type == 1 {
                     util::lookup1_values(entries, dimensions as u32)
                 } else {
-                    entries * dimensions as u32
+                    vectors_len as u32
                 };
                 let multiplicands: Vec<u32> = (0..lookup_values)
                     .map(|_| reader.read(value_bits as u32))
                     .collect::<Result<_, _>>()?;
 
-                Some(VectorLookupTable {
+                let mut vector_lookup_table = VectorLookupTable {
                     minimum_value,
                     delta_value,
                     value_bits,
                     sequence_p,
                     lookup_values,
                     multiplicands,
-                })
+                    vectors: Vec::new(),
+                };
+                vector_lookup_table.unpack_vectors(lookup_type, entries, dimensions);
+                Some(vector_lookup_table)
             }
             x => return Err(CodebookError::InvalidLookupType(x)),
         };
 
         // Set up Huffman tree
-        let mut huffman_tree = HuffmanTree::new();
-        for (value, length) in codeword_lengths.iter().enumerate() {
-            if let Some(len) = length {
-                huffman_tree.add_node(*len, value as u32);
-            }
-        }
+        let huffman_tree = match HuffmanTree::from_lengths(&codeword_lengths) {
+            Ok(huffman_tree) => huffman_tree,
+            Err(HuffmanError::Overspecified) => return Err(CodebookError::OverspecifiedTree),
+            Err(HuffmanError::Underspecified) => return Err(CodebookError::UnderspecifiedTree),
+            Err(e) => return Err(e.into()),
+        };
 
         Ok(Self {
             dimensions,
@@ -118,6 +143,50 @@ impl Codebook {
             huffman_tree,
         })
     }
+
+    pub fn dimensions(&self) -> u16 {
+        self.dimensions
+    }
+
+    /// The length of each entry's codeword, or `None` for unused entries.
+    pub fn codeword_lengths(&self) -> &[Option<u8>] {
+        &self.codeword_lengths
+    }
+
+    pub fn lookup_type(&self) -> u8 {
+        self.lookup_type
+    }
+
+    /// Read a codeword from the bitstream and return its entry number.
+    pub fn read_scalar(&self, reader: &mut PacketBitReader) -> Result<u32, CodebookError> {
+        // Keep I/O errors as they are so that callers can tell when the end of
+        // the packet has been reached.
+        match self.huffman_tree.decode_entry(reader) {
+            Ok(entry) => Ok(entry),
+            Err(HuffmanError::IOError(e)) => Err(e.into()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Return the VQ vector for an entry, which has `dimensions` values.
+    pub fn vector(&self, entry: u32) -> Result<&[f32], CodebookError> {
+        let vector_lookup_table = self
+            .vector_lookup_table
+            .as_ref()
+            .ok_or(CodebookError::NoVectorLookup)?;
+        let dimensions = self.dimensions as usize;
+        let start = entry as usize * dimensions;
+        vector_lookup_table
+            .vectors
+            .get(start..start + dimensions)
+            .ok_or(CodebookError::InvalidEntry(entry))
+    }
+
+    /// Read a codeword from the bitstream and return the VQ vector for its entry.
+    pub fn read_vector(&self, reader: &mut PacketBitReader) -> Result<&[f32], CodebookError> {
+        let entry = self.read_scalar(reader)?;
+        self.vector(entry)
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -128,6 +197,59 @@ pub struct VectorLookupTable {
     sequence_p: bool,
     lookup_values: u32,
     multiplicands: Vec<u32>,
+    vectors: Vec<f32>, // The vector for every entry, one after another
+}
+
+impl VectorLookupTable {
+    /// Work out the vector for every entry up front, so that decoding a vector
+    /// is just a lookup.
+    fn unpack_vectors(&mut self, lookup_type: u8, entries: u32, dimensions: u16) {
+        let mut vectors = Vec::with_capacity(entries as usize * dimensions as usize);
+        for entry in 0..entries {
+            let vector = match lookup_type {
+                1 => self.lattice_vector(entry, dimensions),
+                _ => self.tessellated_vector(entry, dimensions),
+            };
+            vectors.extend(vector);
+        }
+        self.vectors = vectors;
+    }
+
+    /// Unpack the vector for `entry` from a lookup type 1 table, where every
+    /// vector element is an index into the same set of multiplicands.
+    fn lattice_vector(&self, entry: u32, dimensions: u16) -> Vec<f32> {
+        let mut last = 0.0;
+        let mut index_divisor = 1;
+        let mut vector = Vec::with_capacity(dimensions as usize);
+        for _ in 0..dimensions {
+            let multiplicand_offset = (entry / index_divisor) % self.lookup_values;
+            let value = self.multiplicands[multiplicand_offset as usize] as f32 * self.delta_value
+                + self.minimum_value
+                + last;
+            vector.push(value);
+            if self.sequence_p == true {
+                last = value;
+            }
+            index_divisor = index_divisor.saturating_mul(self.lookup_values);
+        }
+        vector
+    }
+
+    /// Unpack the vector for `entry` from a lookup type 2 table, where each
+    /// entry has its own set of multiplicands.
+    fn tessellated_vector(&self, entry: u32, dimensions: u16) -> Vec<f32> {
+        let mut last = 0.0;
+        let offset = entry as usize * dimensions as usize;
+        let mut vector = Vec::with_capacity(dimensions as usize);
+        for &multiplicand in &self.multiplicands[offset..offset + dimensions as usize] {
+            let value = multiplicand as f32 * self.delta_value + self.minimum_value + last;
+            vector.push(value);
+            if self.sequence_p == true {
+                last = value;
+            }
+        }
+        vector
+    }
 }
 
 #[derive(Debug, Error)]
@@ -138,13 +260,43 @@ pub enum CodebookError {
     #[error("Too many entries: {0}")]
     TooManyEntries(u32),
 
+    #[error("Codeword length over 32: {0}")]
+    CodewordTooLong(u8),
+
     #[error("Invalid lookup type: {0}")]
     InvalidLookupType(u8),
 
+    #[error("Overspecified Huffman tree")]
+    OverspecifiedTree,
+
+    #[error("Underspecified Huffman tree")]
+    UnderspecifiedTree,
+
+    #[error("Vector lookup table too large: {0} values")]
+    VectorTableTooLarge(u64),
+
+    #[error("Vector lookup value out of range: {0}")]
+    ValueOutOfRange(f32),
+
+    #[error("Codebook has no vector lookup table")]
+    NoVectorLookup,
+
+    #[error("Invalid entry: {0}")]
+    InvalidEntry(u32),
+
+    #[error(transparent)]
+    Huffman(#[from] HuffmanError),
+
     #[error(transparent)]
     IOError(#[from] std::io::Error),
 }
 
+impl UnexpectedEof for CodebookError {
+    fn is_unexpected_eof(&self) -> bool {
+        matches!(self, Self::IOError(e) if e.is_unexpected_eof() == true)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -190,13 +342,21 @@ mod test {
         let err = Codebook::decode(&mut reader).unwrap_err();
         assert!(matches!(err, CodebookError::InvalidSyncPattern([1, 2, 3])));
 
-        // Too many entries
-        let input = [66, 67, 86, 1, 0, 8, 0, 0, 61];
+        // Too many entries: 15 codewords of length 1 in an ordered codebook of 8
+        let input = [66, 67, 86, 1, 0, 8, 0, 0, 193, 3];
         let mut cursor = Cursor::new(input);
         let mut reader = BitReader::endian(&mut cursor, LittleEndian);
         let err = Codebook::decode(&mut reader).unwrap_err();
         assert!(matches!(err, CodebookError::TooManyEntries(15)));
 
+        // Codewords longer than 32 bits: an ordered codebook of 8 starting at
+        // length 32, with no codewords of that length
+        let input = [66, 67, 86, 1, 0, 8, 0, 0, 63, 0];
+        let mut cursor = Cursor::new(input);
+        let mut reader = BitReader::endian(&mut cursor, LittleEndian);
+        let err = Codebook::decode(&mut reader).unwrap_err();
+        assert!(matches!(err, CodebookError::CodewordTooLong(33)));
+
         // Invalid lookup type
         let input = [66, 67, 86, 1, 0, 8, 0, 0, 0, 49, 76, 32, 197, 188]; // Change lookup_type to 0b1111
         let mut cursor = Cursor::new(input);
@@ -214,4 +374,158 @@ mod test {
             x => panic!("Unexpected result: {:?}", x),
         }
     }
+
+    #[test]
+    fn test_codebook_read_scalar() {
+        use bitstream_io::{BitReader, LittleEndian};
+        use std::io::Cursor;
+
+        // Frampton codebook 0
+        let input = [66, 67, 86, 1, 0, 8, 0, 0, 0, 49, 76, 32, 197, 128];
+        let mut cursor = Cursor::new(input);
+        let mut reader = BitReader::endian(&mut cursor, LittleEndian);
+        let codebook = Codebook::decode(&mut reader).unwrap();
+
+        // Codewords 0, 100, 11 and then a partial codeword
+        let input = [0b01110010];
+        let mut reader = PacketBitReader::new(&input);
+        assert_eq!(codebook.read_scalar(&mut reader).unwrap(), 0);
+        assert_eq!(codebook.read_scalar(&mut reader).unwrap(), 1);
+        assert_eq!(codebook.read_scalar(&mut reader).unwrap(), 4);
+        let err = codebook.read_scalar(&mut reader).unwrap_err();
+        match err {
+            CodebookError::IOError(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_codebook_decode_huffman_tree() {
+        use crate::test_stream;
+        use bitstream_io::{BitReader, LittleEndian};
+        use std::io::Cursor;
+
+        let decode = |lengths: &[u32]| {
+            let input = test_stream::codebook(1, lengths, None);
+            let mut cursor = Cursor::new(input);
+            let mut reader = BitReader::endian(&mut cursor, LittleEndian);
+            Codebook::decode(&mut reader)
+        };
+
+        assert!(decode(&[2, 4, 4, 4, 4, 2, 3, 3]).is_ok());
+
+        // Single entry codebooks are allowed
+        assert!(decode(&[1]).is_ok());
+        assert!(decode(&[4]).is_ok());
+
+        let err = decode(&[2, 4, 4, 4, 4, 2, 3, 3, 3]).unwrap_err();
+        assert!(matches!(err, CodebookError::OverspecifiedTree));
+
+        let err = decode(&[2, 4, 4, 4, 4, 2, 3]).unwrap_err();
+        assert!(matches!(err, CodebookError::UnderspecifiedTree));
+    }
+
+    #[test]
+    fn test_codebook_decode_ordered() {
+        use crate::test_stream;
+        use bitstream_io::{BitReader, LittleEndian};
+        use std::io::Cursor;
+
+        let decode = |lengths: &[u32]| {
+            let input = test_stream::ordered_codebook(1, lengths, None);
+            let mut cursor = Cursor::new(input);
+            let mut reader = BitReader::endian(&mut cursor, LittleEndian);
+            Codebook::decode(&mut reader).unwrap()
+        };
+
+        // The first length takes 5 bits, so it can start well above 2
+        let lengths = [3, 3, 3, 3, 3, 3, 3, 4, 4];
+        let codebook = decode(&lengths);
+        assert_eq!(codebook.ordered, true);
+        let expected: Vec<Option<u8>> = lengths.iter().map(|&l| Some(l as u8)).collect();
+        assert_eq!(codebook.codeword_lengths, expected);
+
+        // Lengths with no codewords are skipped over
+        let lengths = [2, 2, 2, 4, 4, 4, 4];
+        let codebook = decode(&lengths);
+        let expected: Vec<Option<u8>> = lengths.iter().map(|&l| Some(l as u8)).collect();
+        assert_eq!(codebook.codeword_lengths, expected);
+
+        // Codewords 00, 1100 and 10 in the codebook above
+        let input = [0b01001100];
+        let mut reader = PacketBitReader::new(&input);
+        assert_eq!(codebook.read_scalar(&mut reader).unwrap(), 0);
+        assert_eq!(codebook.read_scalar(&mut reader).unwrap(), 3);
+        assert_eq!(codebook.read_scalar(&mut reader).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_codebook_vector() {
+        use crate::test_stream;
+        use bitstream_io::{BitReader, LittleEndian};
+        use std::io::Cursor;
+
+        let decode = |dimensions: u32, lengths: &[u32], lookup: Option<(u32, &[u32])>| {
+            let input = test_stream::codebook(dimensions, lengths, lookup);
+            let mut cursor = Cursor::new(input);
+            let mut reader = BitReader::endian(&mut cursor, LittleEndian);
+            Codebook::decode(&mut reader)
+        };
+
+        // Lattice: minimum -1 and delta 1, with each dimension picking one of
+        // the multiplicands
+        let codebook = decode(2, &[2, 2, 2, 2], Some((1, &[0, 3]))).unwrap();
+        assert_eq!(codebook.vector(0).unwrap(), [-1.0, -1.0]);
+        assert_eq!(codebook.vector(1).unwrap(), [2.0, -1.0]);
+        assert_eq!(codebook.vector(2).unwrap(), [-1.0, 2.0]);
+        assert_eq!(codebook.vector(3).unwrap(), [2.0, 2.0]);
+        assert!(matches!(
+            codebook.vector(4).unwrap_err(),
+            CodebookError::InvalidEntry(4)
+        ));
+
+        // Codewords 11 and 10
+        let input = [0b00000111];
+        let mut reader = PacketBitReader::new(&input);
+        assert_eq!(codebook.read_vector(&mut reader).unwrap(), [2.0, 2.0]);
+        assert_eq!(codebook.read_vector(&mut reader).unwrap(), [-1.0, 2.0]);
+
+        // Tessellated: minimum -1.5 and delta 0.25, with each value added on
+        // to the one before it
+        let codebook = decode(2, &[1, 1], Some((2, &[1, 2, 3, 4]))).unwrap();
+        assert_eq!(codebook.vector(0).unwrap(), [-1.25, -2.25]);
+        assert_eq!(codebook.vector(1).unwrap(), [-0.75, -1.25]);
+
+        // No lookup
+        let codebook = decode(1, &[1, 1], None).unwrap();
+        assert!(matches!(
+            codebook.vector(0).unwrap_err(),
+            CodebookError::NoVectorLookup
+        ));
+
+        // Too many values to unpack
+        let lengths = [9; 257];
+        let err = decode(0xFFFF, &lengths, Some((1, &[0]))).unwrap_err();
+        assert!(matches!(err, CodebookError::VectorTableTooLarge(16842495)));
+
+        // Lookup type 2 has a value for every dimension of every entry, which
+        // here is more than a u32 can count
+        let lengths = vec![17; 0x20000];
+        let err = decode(0xFFFF, &lengths, Some((2, &[]))).unwrap_err();
+        assert!(matches!(
+            err,
+            CodebookError::VectorTableTooLarge(0x1FFFE0000)
+        ));
+
+        // A minimum value with an exponent too large for an f32, after the
+        // 90 bits up to and including the lookup type
+        let mut input = test_stream::codebook(2, &[2, 2, 2, 2], Some((1, &[0, 3])));
+        for bit in 90 + 21..90 + 31 {
+            input[bit / 8] |= 1 << (bit % 8);
+        }
+        let mut cursor = Cursor::new(input);
+        let mut reader = BitReader::endian(&mut cursor, LittleEndian);
+        let err = Codebook::decode(&mut reader).unwrap_err();
+        assert!(matches!(err, CodebookError::ValueOutOfRange(f) if f.is_infinite()));
+    }
 }
@@ -0,0 +1,1428 @@
+use crate::{
+    audio::{AudioError, AudioPacket},
+    bit_reader::PacketBitReader,
+    mdct::Mdct,
+    ogg::{LogicalStream, OggPacketReader, PageErrorPolicy},
+    vorbis::{self, CommentHeader, IdHeader, SetupHeader, VorbisError},
+    window::{OverlapAdd, Window},
+};
+use std::{
+    collections::VecDeque,
+    convert::TryFrom,
+    io::{Read, Seek},
+    time::Duration,
+};
+
+pub struct VorbisDecoder<R: Read> {
+    packet_reader: OggPacketReader<R>,
+    id_header: IdHeader,
+    comment_header: CommentHeader,
+    setup_header: SetupHeader,
+    mdct: [Mdct; 2],
+    window: Window,
+    overlap_add: OverlapAdd,
+    /// Serial number of the logical stream being decoded
+    stream_serial_number: u32,
+    /// Byte offset of the first audio page
+    audio_start: u64,
+    /// Packets read ahead of decoding them
+    pending_packets: VecDeque<Packet>,
+    /// Granule position of the first sample returned
+    first_sample_offset: u64,
+    /// Number of samples per channel still to be dropped from the start
+    samples_to_skip: u64,
+    /// Number of samples per channel returned so far
+    samples_decoded: u64,
+    /// Header packets of the next logical stream read so far
+    header_packets: Vec<Vec<u8>>,
+    /// Whether the first audio page of the stream still has to be read
+    first_page_pending: bool,
+    /// Whether the switch to a new logical stream is still to be reported
+    stream_changed: bool,
+    /// Whether that stream has a different number of channels or sample rate
+    format_changed: bool,
+}
+
+/// A packet along with the page information that applies to it.
+struct Packet {
+    data: Vec<u8>,
+    granule_position: Option<u64>,
+    is_last: bool,
+    /// Whether packets were lost before this one
+    follows_gap: bool,
+    /// Whether this is the identification header of a new logical stream
+    starts_stream: bool,
+}
+
+impl<R: Read> VorbisDecoder<R> {
+    /// Create a decoder for the Ogg Vorbis stream in `reader`. The
+    /// identification, comment and setup headers are read before returning.
+    /// Damaged and missing pages are skipped over.
+    pub fn new(reader: R) -> Result<Self, VorbisError> {
+        Self::with_page_error_policy(reader, PageErrorPolicy::default())
+    }
+
+    /// Like `new`, but with a choice of how damaged and missing pages are
+    /// handled.
+    pub fn with_page_error_policy(reader: R, policy: PageErrorPolicy) -> Result<Self, VorbisError> {
+        let mut packet_reader = header_packet_reader(reader);
+        packet_reader.set_page_error_policy(policy);
+        let mut header_packets = Vec::new();
+        read_header_packets(&mut packet_reader, &mut header_packets)?;
+        let headers = parse_headers(&header_packets)?;
+        let mut decoder = Self::from_headers(packet_reader, headers);
+        decoder.read_first_page()?;
+        Ok(decoder)
+    }
+
+    /// Set up decoding with the headers read by `packet_reader`. The first
+    /// audio page is read once decoding starts.
+    pub(crate) fn from_headers(
+        packet_reader: OggPacketReader<R>,
+        (id_header, comment_header, setup_header): (IdHeader, CommentHeader, SetupHeader),
+    ) -> Self {
+        let stream_serial_number = packet_reader.stream_serial_number().unwrap_or_default();
+        let blocksize_0 = id_header.blocksize_0() as usize;
+        let blocksize_1 = id_header.blocksize_1() as usize;
+        let audio_start = packet_reader.position();
+
+        Self {
+            packet_reader,
+            id_header,
+            comment_header,
+            setup_header,
+            mdct: [Mdct::new(blocksize_0), Mdct::new(blocksize_1)],
+            window: Window::new(blocksize_0, blocksize_1),
+            overlap_add: OverlapAdd::new(),
+            stream_serial_number,
+            audio_start,
+            pending_packets: VecDeque::new(),
+            first_sample_offset: 0,
+            samples_to_skip: 0,
+            samples_decoded: 0,
+            header_packets: Vec::new(),
+            first_page_pending: true,
+            stream_changed: false,
+            format_changed: false,
+        }
+    }
+
+    /// Read ahead to the first audio packet with a granule position, and
+    /// compare it with the number of samples the packets up to it produce.
+    /// If the granule position is larger the stream starts part way in; if
+    /// it's smaller, the difference is dropped from the start of the audio.
+    fn read_first_page(&mut self) -> Result<(), VorbisError> {
+        if let (samples, Some(granule_position)) = self.read_ahead()? {
+            if granule_position >= samples {
+                self.first_sample_offset = granule_position - samples;
+            } else {
+                self.samples_to_skip = samples - granule_position;
+            }
+        }
+        self.first_page_pending = false;
+        Ok(())
+    }
+
+    /// Read packets ahead up to the next one with a granule position, and
+    /// return that along with the number of samples the packets produce. The
+    /// first packet only primes the overlap-add, so it counts for nothing.
+    /// The granule position is `None` if the stream ends first, including at
+    /// the last page, where the granule position may cut the audio short, or
+    /// at the start of the next stream of a chained file.
+    ///
+    /// Packets already read ahead are counted first, so this can be called
+    /// again after the reader fails part way through.
+    fn read_ahead(&mut self) -> Result<(u64, Option<u64>), VorbisError> {
+        let mut samples = 0;
+        let mut previous_blockflag = None;
+        let mut index = 0;
+        loop {
+            if index == self.pending_packets.len() {
+                match self.read_packet()? {
+                    Some(packet) => self.pending_packets.push_back(packet),
+                    None => break,
+                }
+            }
+            let packet = &self.pending_packets[index];
+            index += 1;
+
+            // Errors are left for decoding the packet to report
+            if let Ok(blockflag) = AudioPacket::read_blockflag(&packet.data, &self.setup_header) {
+                if let Some(previous_blockflag) = previous_blockflag {
+                    samples +=
+                        self.blocksize(previous_blockflag) / 4 + self.blocksize(blockflag) / 4;
+                }
+                previous_blockflag = Some(blockflag);
+            }
+
+            if packet.is_last == true || packet.starts_stream == true {
+                break;
+            }
+            if packet.granule_position.is_some() {
+                return Ok((samples, packet.granule_position));
+            }
+        }
+        Ok((samples, None))
+    }
+
+    fn blocksize(&self, blockflag: bool) -> u64 {
+        if blockflag == true {
+            self.id_header.blocksize_1() as u64
+        } else {
+            self.id_header.blocksize_0() as u64
+        }
+    }
+
+    /// The next packet, either read ahead already or from the stream.
+    fn next_packet(&mut self) -> Result<Option<Packet>, VorbisError> {
+        match self.pending_packets.pop_front() {
+            Some(packet) => Ok(Some(packet)),
+            None => self.read_packet(),
+        }
+    }
+
+    fn read_packet(&mut self) -> Result<Option<Packet>, VorbisError> {
+        let data = match self.packet_reader.next_packet()? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        Ok(Some(Packet {
+            data,
+            granule_position: self.packet_reader.granule_position(),
+            is_last: self.packet_reader.is_last_packet(),
+            follows_gap: self.packet_reader.follows_gap(),
+            starts_stream: self.packet_reader.starts_stream(),
+        }))
+    }
+
+    pub fn id_header(&self) -> &IdHeader {
+        &self.id_header
+    }
+
+    pub fn comment_header(&self) -> &CommentHeader {
+        &self.comment_header
+    }
+
+    pub fn setup_header(&self) -> &SetupHeader {
+        &self.setup_header
+    }
+
+    /// Decode the next audio packet and return its finished samples, one
+    /// vector per channel. The first packet only primes the decoder, so it
+    /// returns no samples. Samples are dropped from the start and the end of
+    /// the stream where the granule positions of the first and last pages say
+    /// the audio starts later or ends earlier. Returns `None` at the end of
+    /// the stream.
+    ///
+    /// Decoding carries straight on into the next stream of a chained file,
+    /// unless it has a different number of channels or sample rate. Then this
+    /// fails with `FormatChanged` until `next_event` has reported the change.
+    pub fn next_pcm(&mut self) -> Result<Option<Vec<Vec<f32>>>, VorbisError> {
+        loop {
+            match self.decode_next(false)? {
+                Some(DecoderEvent::Pcm(pcm)) => return Ok(Some(pcm)),
+                Some(DecoderEvent::StreamChanged { .. }) | Some(DecoderEvent::PacketsLost) => (),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Like `next_pcm`, but also reports the start of each new logical
+    /// stream in a chained file, after reading its headers, and packets lost
+    /// to damaged or missing pages.
+    pub fn next_event(&mut self) -> Result<Option<DecoderEvent>, VorbisError> {
+        self.decode_next(true)
+    }
+
+    /// Decode up to the next event. A change of format that `report_format`
+    /// doesn't allow to be reported is left pending and fails instead.
+    fn decode_next(&mut self, report_format: bool) -> Result<Option<DecoderEvent>, VorbisError> {
+        loop {
+            if self.header_packets.is_empty() == false {
+                self.start_stream()?;
+            }
+            if self.first_page_pending == true {
+                self.read_first_page()?;
+            }
+            if self.stream_changed == true {
+                if self.format_changed == true && report_format == false {
+                    return Err(VorbisError::FormatChanged {
+                        channels: self.id_header.audio_channels(),
+                        sample_rate: self.id_header.audio_sample_rate(),
+                    });
+                }
+                self.stream_changed = false;
+                self.format_changed = false;
+                return Ok(Some(DecoderEvent::StreamChanged {
+                    channels: self.id_header.audio_channels(),
+                    sample_rate: self.id_header.audio_sample_rate(),
+                    comment_header: self.comment_header.clone(),
+                }));
+            }
+
+            let packet = match self.next_packet()? {
+                Some(packet) => packet,
+                None => return Ok(None),
+            };
+            if packet.starts_stream == true {
+                self.header_packets.push(packet.data);
+                continue;
+            }
+            if packet.follows_gap == true {
+                // The blocks to overlap with are gone, so start over, and
+                // decode the packet once the loss has been reported
+                self.overlap_add = OverlapAdd::new();
+                self.pending_packets.push_front(Packet {
+                    follows_gap: false,
+                    ..packet
+                });
+                return Ok(Some(DecoderEvent::PacketsLost));
+            }
+            if packet.data.is_empty() == true {
+                continue;
+            }
+
+            let mut reader = PacketBitReader::new(&packet.data);
+            let audio_packet = match AudioPacket::decode(
+                &mut reader,
+                &self.id_header,
+                &self.setup_header,
+                &self.mdct,
+                &self.window,
+            ) {
+                Ok(audio_packet) => audio_packet,
+                Err(AudioError::NotAudioPacket) => continue, // Non-audio packets are ignored
+                Err(e) => return Err(e.into()),
+            };
+            let mut pcm = self.overlap_add.push(audio_packet.channels());
+            self.trim_start(&mut pcm);
+            self.trim_end(&mut pcm, &packet);
+            self.samples_decoded += pcm.first().map_or(0, |channel| channel.len()) as u64;
+            return Ok(Some(DecoderEvent::Pcm(pcm)));
+        }
+    }
+
+    /// Read the rest of the headers of the next logical stream and switch
+    /// over to it. The header packets are kept until all three are in, so
+    /// this can be called again after the reader fails part way through.
+    fn start_stream(&mut self) -> Result<(), VorbisError> {
+        read_header_packets(&mut self.packet_reader, &mut self.header_packets)?;
+        let header_packets = std::mem::take(&mut self.header_packets);
+        let (id_header, comment_header, setup_header) = parse_headers(&header_packets)?;
+
+        let blocksize_0 = id_header.blocksize_0() as usize;
+        let blocksize_1 = id_header.blocksize_1() as usize;
+        self.mdct = [Mdct::new(blocksize_0), Mdct::new(blocksize_1)];
+        self.window = Window::new(blocksize_0, blocksize_1);
+        self.format_changed = id_header.audio_channels() != self.id_header.audio_channels()
+            || id_header.audio_sample_rate() != self.id_header.audio_sample_rate();
+        self.id_header = id_header;
+        self.comment_header = comment_header;
+        self.setup_header = setup_header;
+        self.stream_serial_number = self
+            .packet_reader
+            .stream_serial_number()
+            .unwrap_or_default();
+        self.audio_start = self.packet_reader.position();
+
+        self.reset();
+        self.first_sample_offset = 0;
+        self.samples_decoded = 0;
+        self.first_page_pending = true;
+        self.stream_changed = true;
+        Ok(())
+    }
+
+    /// Granule position of the first sample returned by `next_pcm`. This is
+    /// nonzero for streams that start part way into the audio, such as live
+    /// captures.
+    pub fn first_sample_offset(&self) -> u64 {
+        self.first_sample_offset
+    }
+
+    /// Number of samples per channel returned so far from the current
+    /// logical stream.
+    pub fn samples_decoded(&self) -> u64 {
+        self.samples_decoded
+    }
+
+    /// The reader the stream comes from.
+    pub(crate) fn get_mut(&mut self) -> &mut R {
+        self.packet_reader.get_mut()
+    }
+
+    /// Number of damaged or stray bytes skipped to find the next page.
+    pub fn bytes_discarded(&self) -> u64 {
+        self.packet_reader.bytes_discarded()
+    }
+
+    /// The logical streams multiplexed with the Vorbis stream, such as video
+    /// in the same file.
+    pub fn other_streams(&self) -> impl Iterator<Item = &LogicalStream> {
+        self.packet_reader.other_streams()
+    }
+
+    /// Take the next packet read so far from the logical stream `serial`.
+    pub fn next_other_packet(&mut self, serial: u32) -> Option<Vec<u8>> {
+        self.packet_reader.other_stream_mut(serial)?.next_packet()
+    }
+
+    /// Set whether to keep the packets of the other logical streams until
+    /// they're taken with `next_other_packet`. They're dropped by default,
+    /// including those read along with the headers before this is called.
+    pub fn set_keep_other_packets(&mut self, keep: bool) {
+        self.packet_reader.set_keep_other_packets(keep);
+    }
+
+    /// Forget everything about the blocks before a seek or a new stream.
+    fn reset(&mut self) {
+        self.overlap_add = OverlapAdd::new();
+        self.pending_packets.clear();
+        self.header_packets.clear();
+        self.samples_to_skip = 0;
+    }
+
+    /// Drop whatever is left of the samples before the start of the audio.
+    fn trim_start(&mut self, pcm: &mut [Vec<f32>]) {
+        let len = pcm.first().map_or(0, |channel| channel.len());
+        let skip = len.min(self.samples_to_skip as usize);
+        for channel in pcm {
+            channel.drain(..skip);
+        }
+        self.samples_to_skip -= skip as u64;
+    }
+
+    /// Drop the samples of the stream's last packet that are past the end
+    /// given by its granule position.
+    fn trim_end(&self, pcm: &mut [Vec<f32>], packet: &Packet) {
+        if packet.is_last == false {
+            return;
+        }
+        let granule_position = match packet.granule_position {
+            Some(granule_position) => granule_position,
+            None => return,
+        };
+        let position = self.first_sample_offset + self.samples_decoded;
+        let len = granule_position.saturating_sub(position) as usize;
+        for channel in pcm {
+            channel.truncate(len);
+        }
+    }
+
+    /// Like `next_pcm`, but with the channels interleaved.
+    pub fn next_pcm_interleaved(&mut self) -> Result<Option<Vec<f32>>, VorbisError> {
+        let pcm = match self.next_pcm()? {
+            Some(pcm) => pcm,
+            None => return Ok(None),
+        };
+        let len = pcm.first().map_or(0, |channel| channel.len());
+        let interleaved = (0..len)
+            .flat_map(|i| pcm.iter().map(move |channel| channel[i]))
+            .collect();
+        Ok(Some(interleaved))
+    }
+}
+
+impl<R: Read + Seek> VorbisDecoder<R> {
+    /// Seek so that the next sample returned by `next_pcm` is sample number
+    /// `sample`, counting from the first sample of the stream.
+    ///
+    /// This bisects the stream for the last page that ends before the sample,
+    /// then decodes from the page after it. The first packet there only
+    /// primes the overlap-add, and the position of the samples after it comes
+    /// from the next granule position. Samples before the requested one are
+    /// dropped as they're decoded.
+    ///
+    /// The granule position of the last page may cut the stream short, so it
+    /// can't place the packets before it. If the sample is on the last page,
+    /// the last packet of the page found primes the overlap-add instead, and
+    /// the samples after it start at that page's granule position.
+    pub fn seek_to_sample(&mut self, sample: u64) -> Result<(), VorbisError> {
+        let target = self.first_sample_offset + sample;
+        let mut before = target;
+        loop {
+            let (page_start, page_end, granule_position) = match self.find_page_before(before)? {
+                Some(page) => page,
+                None => {
+                    // The sample is on the first audio page, so start over
+                    self.packet_reader.seek_to_page(self.audio_start)?;
+                    self.reset();
+                    self.read_first_page()?;
+                    self.samples_to_skip += sample;
+                    self.samples_decoded = sample;
+                    return Ok(());
+                }
+            };
+
+            self.packet_reader.seek_to_page(page_end)?;
+            self.reset();
+            match self.read_ahead()? {
+                (samples, Some(next_granule_position)) => {
+                    let start = next_granule_position.saturating_sub(samples);
+                    if start <= target {
+                        self.samples_to_skip = target - start;
+                        self.samples_decoded = sample;
+                        return Ok(());
+                    }
+                }
+                (_, None) => {
+                    // The last page, so read the page found again and keep
+                    // just the packet its granule position belongs to
+                    self.packet_reader.seek_to_page(page_start)?;
+                    self.reset();
+                    if let (_, Some(granule_position)) = self.read_ahead()? {
+                        if granule_position <= target {
+                            let last_packet = self.pending_packets.pop_back();
+                            self.pending_packets.clear();
+                            self.pending_packets.extend(last_packet);
+                            self.samples_to_skip = target - granule_position;
+                            self.samples_decoded = sample;
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+
+            // Either the sample is in the packet that primes the overlap-add,
+            // or the page found holds no whole packet, so try an earlier page
+            before = granule_position;
+        }
+    }
+
+    /// Total length, duration and average bitrate of the stream, worked out
+    /// from the granule position of its last page without decoding it.
+    pub fn stream_info(&mut self) -> Result<StreamInfo, VorbisError> {
+        measure_stream(
+            &mut self.packet_reader,
+            &self.id_header,
+            self.stream_serial_number,
+            self.audio_start,
+            self.first_sample_offset,
+        )
+    }
+
+    /// Bisect the audio pages for the last one whose granule position is
+    /// less than `granule_position`. Returns the byte offsets of the start and
+    /// the end of that page, and its granule position.
+    fn find_page_before(
+        &mut self,
+        granule_position: u64,
+    ) -> Result<Option<(u64, u64, u64)>, VorbisError> {
+        let mut low = self.audio_start;
+        let mut high = self.packet_reader.stream_len()?;
+        let mut found = None;
+        while low < high {
+            let middle = low + (high - low) / 2;
+
+            // Pages where no packet ends have no granule position to compare,
+            // and pages of the next stream in a chained file don't count
+            let mut position = middle;
+            let page = loop {
+                match self.packet_reader.find_page(position, high)? {
+                    Some((offset, page))
+                        if page.absolute_granule_position() == u64::MAX
+                            || page.stream_serial_number() != self.stream_serial_number =>
+                    {
+                        position = offset + page.byte_len() as u64;
+                    }
+                    page => break page,
+                }
+            };
+
+            match page {
+                Some((offset, page)) if page.absolute_granule_position() < granule_position => {
+                    let page_end = offset + page.byte_len() as u64;
+                    found = Some((offset, page_end, page.absolute_granule_position()));
+                    low = page_end;
+                }
+                _ => high = middle,
+            }
+        }
+        Ok(found)
+    }
+}
+
+/// Length and bitrate of a stream, from `stream_info` or
+/// `VorbisDecoder::stream_info`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamInfo {
+    /// Number of samples per channel that decoding the stream returns
+    pub total_samples: u64,
+    pub duration: Duration,
+    /// Average bitrate in bits per second, if known
+    pub bitrate: Option<u32>,
+}
+
+/// What `VorbisDecoder::next_event` found next in the stream.
+#[derive(Debug)]
+pub enum DecoderEvent {
+    /// Finished samples, one vector per channel
+    Pcm(Vec<Vec<f32>>),
+    /// A new logical stream started in a chained file. Its headers replace
+    /// those of the stream before it.
+    StreamChanged {
+        channels: u8,
+        sample_rate: u32,
+        comment_header: CommentHeader,
+    },
+    /// Packets were lost to damaged or missing pages, which the page error
+    /// policy skipped. Decoding starts over from the next packet, which
+    /// only primes the decoder.
+    PacketsLost,
+}
+
+/// Total length, duration and average bitrate of the Ogg Vorbis stream in
+/// `reader`, like `VorbisDecoder::stream_info` but without setting up a
+/// decoder. Only the identification header is parsed, so a stream that starts
+/// part way in is counted from granule position 0.
+pub fn stream_info<R: Read + Seek>(reader: R) -> Result<StreamInfo, VorbisError> {
+    let mut packet_reader = header_packet_reader(reader);
+    let packet = next_header_packet(&mut packet_reader)?;
+    let id_header = IdHeader::from_packet(&packet)?;
+
+    // The comment and setup headers are only read to find where the audio starts
+    next_header_packet(&mut packet_reader)?;
+    next_header_packet(&mut packet_reader)?;
+    let serial = packet_reader.stream_serial_number().unwrap_or_default();
+    let audio_start = packet_reader.position();
+    measure_stream(&mut packet_reader, &id_header, serial, audio_start, 0)
+}
+
+/// Length, duration and bitrate of the stream with serial number `serial`,
+/// whose audio starts at byte `audio_start` and granule position
+/// `first_sample_offset`.
+fn measure_stream<R: Read + Seek>(
+    packet_reader: &mut OggPacketReader<R>,
+    id_header: &IdHeader,
+    serial: u32,
+    audio_start: u64,
+    first_sample_offset: u64,
+) -> Result<StreamInfo, VorbisError> {
+    let last_page = packet_reader.find_last_page(serial)?;
+    let (total_samples, audio_end) = match last_page {
+        Some((offset, page)) => (
+            page.absolute_granule_position()
+                .saturating_sub(first_sample_offset),
+            offset + page.byte_len() as u64,
+        ),
+        None => (0, audio_start),
+    };
+    let sample_rate = id_header.audio_sample_rate() as u64;
+    let seconds = total_samples as f64 / sample_rate as f64;
+    let duration = Duration::try_from_secs_f64(seconds).unwrap_or(Duration::MAX);
+
+    // Measure the bitrate from the size of the audio pages, falling back
+    // on what the identification header says
+    let audio_len = audio_end.saturating_sub(audio_start);
+    let measured = (audio_len as u128 * 8 * sample_rate as u128).checked_div(total_samples as u128);
+    let bitrate = if let Some(bitrate) = measured {
+        Some(u32::try_from(bitrate).unwrap_or(u32::MAX))
+    } else if id_header.bitrate_nominal() > 0 {
+        Some(id_header.bitrate_nominal() as u32)
+    } else if id_header.bitrate_maximum() > 0 && id_header.bitrate_minimum() > 0 {
+        let sum = id_header.bitrate_maximum() as u32 + id_header.bitrate_minimum() as u32;
+        Some(sum / 2)
+    } else {
+        None
+    };
+
+    Ok(StreamInfo {
+        total_samples,
+        duration,
+        bitrate,
+    })
+}
+
+/// Whether `packet` is a Vorbis identification header, which starts every
+/// Vorbis stream.
+fn is_id_header(packet: &[u8]) -> bool {
+    vorbis::check_header(packet, 1).is_ok()
+}
+
+/// A packet reader for the first Vorbis stream in `reader`.
+pub(crate) fn header_packet_reader<R: Read>(reader: R) -> OggPacketReader<R> {
+    let mut packet_reader = OggPacketReader::new(reader);
+    packet_reader.select_stream(is_id_header);
+    packet_reader
+}
+
+/// Read the rest of the three header packets into `header_packets`. They're
+/// kept there as they come in, so this can be called again after the reader
+/// fails part way through.
+pub(crate) fn read_header_packets<R: Read>(
+    packet_reader: &mut OggPacketReader<R>,
+    header_packets: &mut Vec<Vec<u8>>,
+) -> Result<(), VorbisError> {
+    while header_packets.len() < 3 {
+        let packet = next_header_packet(packet_reader)?;
+        vorbis::check_header(&packet, [1, 3, 5][header_packets.len()])?;
+        header_packets.push(packet);
+    }
+    Ok(())
+}
+
+/// Parse the identification, comment and setup header packets.
+pub(crate) fn parse_headers(
+    header_packets: &[Vec<u8>],
+) -> Result<(IdHeader, CommentHeader, SetupHeader), VorbisError> {
+    let id_header = IdHeader::from_packet(&header_packets[0])?;
+    let comment_header = CommentHeader::from_packet(&header_packets[1])?;
+    let setup_header = SetupHeader::from_packet(&header_packets[2], &id_header)?;
+    Ok((id_header, comment_header, setup_header))
+}
+
+fn next_header_packet<R: Read>(
+    packet_reader: &mut OggPacketReader<R>,
+) -> Result<Vec<u8>, VorbisError> {
+    packet_reader
+        .next_packet()?
+        .ok_or(VorbisError::MissingHeader)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ogg::OggError;
+    use crate::test_stream::{self, Rng, StreamConfig};
+    use std::io::Cursor;
+
+    // Frampton identification header page
+    const ID_PAGE: [u8; 58] = [
+        0x4F, 0x67, 0x67, 0x53, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x4B,
+        0x86, 0x5C, 0x7D, 0x00, 0x00, 0x00, 0x00, 0xC1, 0xE3, 0xE7, 0xEF, 0x01, 0x1E, 0x01, 0x76,
+        0x6F, 0x72, 0x62, 0x69, 0x73, 0x00, 0x00, 0x00, 0x00, 0x01, 0x44, 0xAC, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x77, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0xB8, 0x01,
+    ];
+
+    #[test]
+    fn test_decoder_missing_header() {
+        let err = VorbisDecoder::new(Cursor::new([])).err().unwrap();
+        assert!(matches!(err, VorbisError::MissingHeader));
+
+        // Only the identification header is present
+        let err = VorbisDecoder::new(Cursor::new(ID_PAGE)).err().unwrap();
+        assert!(matches!(err, VorbisError::MissingHeader));
+    }
+
+    #[test]
+    fn test_decoder_wrong_packet_type() {
+        // Both pages hold identification headers
+        let input = [ID_PAGE, ID_PAGE].concat();
+        let err = VorbisDecoder::new(Cursor::new(input)).err().unwrap();
+        assert!(matches!(
+            err,
+            VorbisError::WrongPacketType {
+                expected: 3,
+                found: 1
+            }
+        ));
+    }
+
+    #[test]
+    fn test_decoder_next_pcm() {
+        for floor_type in [0, 1] {
+            let config = StreamConfig {
+                floor_type,
+                ..Default::default()
+            };
+            let (input, _) = test_stream::build(&config);
+            let mut decoder = VorbisDecoder::new(Cursor::new(input)).unwrap();
+            assert_eq!(decoder.id_header().audio_channels(), 1);
+
+            let mut counts = Vec::new();
+            while let Some(pcm) = decoder.next_pcm().unwrap() {
+                assert_eq!(pcm.len(), 1);
+                assert!(pcm[0].iter().all(|sample| sample.is_finite()));
+                counts.push(pcm[0].len() as u64);
+            }
+            assert_eq!(counts, test_stream::packet_sample_counts(&config.blocks));
+        }
+    }
+
+    #[test]
+    fn test_decoder_next_pcm_channels() {
+        for channels in [2, 6] {
+            let config = StreamConfig {
+                channels,
+                ..Default::default()
+            };
+            let (input, _) = test_stream::build(&config);
+            let mut decoder = VorbisDecoder::new(Cursor::new(input)).unwrap();
+            assert_eq!(decoder.id_header().audio_channels(), channels);
+
+            let mut counts = Vec::new();
+            while let Some(pcm) = decoder.next_pcm().unwrap() {
+                assert_eq!(pcm.len(), channels as usize);
+                for channel in &pcm {
+                    assert_eq!(channel.len(), pcm[0].len());
+                    assert!(channel.iter().all(|sample| sample.is_finite()));
+                }
+                counts.push(pcm[0].len() as u64);
+            }
+            assert_eq!(counts, test_stream::packet_sample_counts(&config.blocks));
+        }
+    }
+
+    #[test]
+    fn test_decoder_reference_pcm() {
+        // A file encoded with libvorbis, and its samples as decoded by lewton
+        let input = include_bytes!("../bin/plop.ogg");
+        let reference: Vec<f32> = include_bytes!("../bin/plop.pcm")
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .collect();
+
+        let mut decoder = VorbisDecoder::new(Cursor::new(input)).unwrap();
+        assert_eq!(decoder.id_header().audio_channels(), 1);
+        let mut pcm = Vec::new();
+        while let Some(packet_pcm) = decoder.next_pcm().unwrap() {
+            pcm.extend_from_slice(&packet_pcm[0]);
+        }
+        assert_eq!(pcm.len(), reference.len());
+        for (i, (sample, expected)) in pcm.iter().zip(&reference).enumerate() {
+            assert!(
+                (sample - expected).abs() < 1e-5,
+                "Sample {}: {} != {}",
+                i,
+                sample,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_decoder_trim_end() {
+        let decode = |config: &StreamConfig| {
+            let (input, _) = test_stream::build(config);
+            let mut decoder = VorbisDecoder::new(Cursor::new(input)).unwrap();
+            let mut pcm = Vec::new();
+            let mut counts = Vec::new();
+            while let Some(packet_pcm) = decoder.next_pcm().unwrap() {
+                counts.push(packet_pcm[0].len() as u64);
+                pcm.extend_from_slice(&packet_pcm[0]);
+            }
+            assert_eq!(decoder.samples_decoded(), pcm.len() as u64);
+            (pcm, counts)
+        };
+
+        let config = StreamConfig::default();
+        let (untrimmed, untrimmed_counts) = decode(&config);
+        let last_count = *untrimmed_counts.last().unwrap();
+        assert_eq!(last_count, 128);
+
+        // Only the end of the last packet is dropped
+        for end_trim in [1, 100, last_count] {
+            let config = StreamConfig {
+                end_trim,
+                ..Default::default()
+            };
+            let (pcm, counts) = decode(&config);
+            assert_eq!(pcm.len() as u64, untrimmed.len() as u64 - end_trim);
+            assert_eq!(pcm[..], untrimmed[..pcm.len()]);
+            assert_eq!(
+                counts[..counts.len() - 1],
+                untrimmed_counts[..counts.len() - 1]
+            );
+            assert_eq!(*counts.last().unwrap(), last_count - end_trim);
+        }
+
+        // Pages where no packet ends don't affect the result
+        let config = StreamConfig {
+            segments_per_page: 1,
+            end_trim: 10,
+            ..Default::default()
+        };
+        let (pcm, _) = decode(&config);
+        assert_eq!(pcm.len(), untrimmed.len() - 10);
+    }
+
+    #[test]
+    fn test_decoder_start_granule() {
+        let decode = |config: &StreamConfig| {
+            let (input, _) = test_stream::build(config);
+            let mut decoder = VorbisDecoder::new(Cursor::new(input)).unwrap();
+            let mut pcm = Vec::new();
+            while let Some(packet_pcm) = decoder.next_pcm().unwrap() {
+                pcm.extend_from_slice(&packet_pcm[0]);
+            }
+            (decoder.first_sample_offset(), pcm)
+        };
+
+        let (offset, untrimmed) = decode(&StreamConfig::default());
+        assert_eq!(offset, 0);
+
+        // A stream that starts part way in keeps all of its samples
+        let config = StreamConfig {
+            start_granule: 44100,
+            ..Default::default()
+        };
+        assert_eq!(decode(&config), (44100, untrimmed.clone()));
+
+        // Along with the end trimmed, which is relative to the start
+        let config = StreamConfig {
+            start_granule: 44100,
+            end_trim: 10,
+            ..Default::default()
+        };
+        assert_eq!(
+            decode(&config),
+            (44100, untrimmed[..untrimmed.len() - 10].to_vec())
+        );
+
+        // A first granule position lower than the samples on the first page
+        // drops the difference from the start, even past the first packet
+        // with samples in it
+        for start_trim in [1, 127, 128, 300] {
+            let config = StreamConfig {
+                start_trim,
+                ..Default::default()
+            };
+            let (offset, pcm) = decode(&config);
+            assert_eq!(offset, 0);
+            assert_eq!(pcm, untrimmed[start_trim as usize..]);
+
+            let config = StreamConfig {
+                start_trim,
+                end_trim: 10,
+                ..Default::default()
+            };
+            let (_, pcm) = decode(&config);
+            assert_eq!(pcm, untrimmed[start_trim as usize..untrimmed.len() - 10]);
+        }
+    }
+
+    #[test]
+    fn test_decoder_seek_to_sample() {
+        fn decode_rest(decoder: &mut VorbisDecoder<Cursor<Vec<u8>>>) -> Vec<Vec<f32>> {
+            let channels = decoder.id_header().audio_channels() as usize;
+            let mut pcm = vec![Vec::new(); channels];
+            while let Some(packet_pcm) = decoder.next_pcm().unwrap() {
+                for (channel, packet_channel) in pcm.iter_mut().zip(packet_pcm) {
+                    channel.extend(packet_channel);
+                }
+            }
+            pcm
+        }
+
+        let mut rng = Rng::new(5);
+        let blocks: Vec<bool> = (0..40).map(|_| rng.below(2) == 1).collect();
+        let configs = [
+            StreamConfig {
+                blocks: blocks.clone(),
+                ..Default::default()
+            },
+            // Packets spread over many pages, most without a granule position
+            StreamConfig {
+                channels: 2,
+                blocks: blocks.clone(),
+                segments_per_page: 1,
+                ..Default::default()
+            },
+            StreamConfig {
+                blocks: blocks.clone(),
+                segments_per_page: 12,
+                start_granule: 1000,
+                end_trim: 100,
+                ..Default::default()
+            },
+            StreamConfig {
+                blocks,
+                start_trim: 50,
+                end_trim: 10,
+                ..Default::default()
+            },
+        ];
+        for config in configs {
+            let (input, _) = test_stream::build(&config);
+            let mut decoder = VorbisDecoder::new(Cursor::new(input)).unwrap();
+            let linear = decode_rest(&mut decoder);
+            let len = linear[0].len() as u64;
+
+            let mut targets = vec![0, 1, 127, 128, 129, len / 2, len - 1, len, len + 100];
+            targets.extend((0..20).map(|_| rng.below(len)));
+            for target in targets {
+                decoder.seek_to_sample(target).unwrap();
+                assert_eq!(decoder.samples_decoded(), target);
+                let pcm = decode_rest(&mut decoder);
+                let start = target.min(len) as usize;
+                for (channel, linear_channel) in pcm.iter().zip(&linear) {
+                    assert_eq!(channel[..], linear_channel[start..], "seek to {}", target);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_decoder_seek_to_sample_reference() {
+        // Pages end at 103104, 208576, 314048, 419520 and 441000 samples
+        let input = include_bytes!("../bin/beep3.ogg");
+        let mut decoder = VorbisDecoder::new(Cursor::new(input.to_vec())).unwrap();
+        let mut linear = Vec::new();
+        while let Some(pcm) = decoder.next_pcm().unwrap() {
+            linear.extend_from_slice(&pcm[0]);
+        }
+        assert_eq!(linear.len(), 441000);
+
+        let targets = [
+            0, 1, 103103, 103104, 103105, 300000, 419519, 419520, 419521, 430000, 440999, 441000,
+        ];
+        for &target in targets.iter() {
+            decoder.seek_to_sample(target).unwrap();
+            // The last page is decoded after just one packet from the page before
+            if target > 419520 {
+                assert_eq!(decoder.pending_packets.len(), 1);
+            }
+            let mut pcm = Vec::new();
+            while let Some(packet_pcm) = decoder.next_pcm().unwrap() {
+                pcm.extend_from_slice(&packet_pcm[0]);
+            }
+            assert_eq!(pcm[..], linear[target as usize..], "seek to {}", target);
+        }
+    }
+
+    #[test]
+    fn test_decoder_resync() {
+        let config = StreamConfig::default();
+        let (input, _) = test_stream::build(&config);
+        let mut decoder = VorbisDecoder::new(Cursor::new(input.clone())).unwrap();
+        let mut pcm = Vec::new();
+        while let Some(packet_pcm) = decoder.next_pcm().unwrap() {
+            pcm.push(packet_pcm);
+        }
+        assert_eq!(decoder.bytes_discarded(), 0);
+
+        // Wipe out the capture pattern of the second last page, which the
+        // decoder has to skip to carry on with the last one
+        let page_starts: Vec<usize> = input
+            .windows(4)
+            .enumerate()
+            .filter(|(_, w)| w == b"OggS")
+            .map(|(i, _)| i)
+            .collect();
+        let [.., damaged, last] = page_starts[..] else {
+            unreachable!()
+        };
+        let mut input = input;
+        input[damaged..damaged + 4].copy_from_slice(b"XXXX");
+
+        let mut decoder = VorbisDecoder::new(Cursor::new(input)).unwrap();
+        let mut damaged_pcm = Vec::new();
+        while let Some(packet_pcm) = decoder.next_pcm().unwrap() {
+            damaged_pcm.push(packet_pcm);
+        }
+        assert_eq!(decoder.bytes_discarded(), (last - damaged) as u64);
+        assert!(damaged_pcm.len() < pcm.len());
+        assert_eq!(damaged_pcm[0], pcm[0]);
+    }
+
+    #[test]
+    fn test_decoder_page_error_policy() {
+        let config = StreamConfig {
+            blocks: vec![
+                false, true, true, false, true, true, false, true, false, false,
+            ],
+            ..Default::default()
+        };
+        let (input, _) = test_stream::build(&config);
+        let decode = |input: &[u8], policy| {
+            let mut decoder = VorbisDecoder::with_page_error_policy(Cursor::new(input), policy)?;
+            let mut pcm = Vec::new();
+            while let Some(packet_pcm) = decoder.next_pcm()? {
+                pcm.push(packet_pcm.concat().len());
+            }
+            Ok::<_, VorbisError>(pcm)
+        };
+        let lens = decode(&input, PageErrorPolicy::Strict).unwrap();
+
+        // Damage the last byte of the third last page
+        let page_starts: Vec<usize> = input
+            .windows(4)
+            .enumerate()
+            .filter(|(_, w)| w == b"OggS")
+            .map(|(i, _)| i)
+            .collect();
+        let mut input = input;
+        input[page_starts[page_starts.len() - 2] - 1] ^= 0xFF;
+
+        let err = decode(&input, PageErrorPolicy::Strict).unwrap_err();
+        assert!(matches!(
+            err,
+            VorbisError::Ogg(OggError::ChecksumMismatch(_))
+        ));
+
+        // Decoding starts over after the damaged page, so the first packet
+        // after it only primes the decoder again
+        let skipped = decode(&input, PageErrorPolicy::Skip).unwrap();
+        assert!(skipped.len() < lens.len());
+        assert_eq!(skipped.iter().filter(|len| **len == 0).count(), 2);
+        assert_eq!(skipped[..2], lens[..2]);
+
+        // The loss is reported where it happened
+        let mut decoder =
+            VorbisDecoder::with_page_error_policy(Cursor::new(&input), PageErrorPolicy::Skip)
+                .unwrap();
+        let mut events = Vec::new();
+        while let Some(event) = decoder.next_event().unwrap() {
+            events.push(match event {
+                DecoderEvent::Pcm(pcm) => Some(pcm.concat().len()),
+                DecoderEvent::PacketsLost => None,
+                event => panic!("{:?}", event),
+            });
+        }
+        let lost = events.iter().position(|event| event.is_none()).unwrap();
+        assert_eq!(events[lost + 1], Some(0));
+        let pcm_lens: Vec<usize> = events.into_iter().flatten().collect();
+        assert_eq!(pcm_lens, skipped);
+
+        // The damaged data is decoded as it is
+        let ignored = decode(&input, PageErrorPolicy::Ignore).unwrap();
+        assert_eq!(ignored, lens);
+    }
+
+    #[test]
+    fn test_decoder_stream_info() {
+        let configs = [
+            StreamConfig::default(),
+            StreamConfig {
+                channels: 2,
+                start_granule: 1000,
+                end_trim: 100,
+                ..Default::default()
+            },
+            StreamConfig {
+                start_trim: 50,
+                ..Default::default()
+            },
+        ];
+        for config in configs {
+            let (mut input, _) = test_stream::build(&config);
+            let mut decoder = VorbisDecoder::new(Cursor::new(input.clone())).unwrap();
+            let audio_len = input.len() as u64 - decoder.audio_start;
+            let mut pcm = Vec::new();
+            while let Some(packet_pcm) = decoder.next_pcm().unwrap() {
+                pcm.extend_from_slice(&packet_pcm[0]);
+            }
+            let total_samples = pcm.len() as u64;
+            let expected = StreamInfo {
+                total_samples,
+                duration: Duration::from_secs_f64(total_samples as f64 / 44100.0),
+                bitrate: Some((audio_len * 8 * 44100 / total_samples) as u32),
+            };
+
+            // Decoding carries on where it was afterwards
+            let mut decoder = VorbisDecoder::new(Cursor::new(input.clone())).unwrap();
+            let first = decoder.next_pcm().unwrap().unwrap();
+            assert_eq!(decoder.stream_info().unwrap(), expected);
+            let mut rest = first[0].clone();
+            while let Some(packet_pcm) = decoder.next_pcm().unwrap() {
+                rest.extend_from_slice(&packet_pcm[0]);
+            }
+            assert_eq!(rest, pcm);
+
+            // Without the setup header, a stream that starts part way in is
+            // counted from granule position 0
+            let info = stream_info(Cursor::new(input.clone())).unwrap();
+            assert_eq!(info.total_samples, total_samples + config.start_granule);
+            if config.start_granule == 0 {
+                assert_eq!(info, expected);
+            }
+
+            // Pages of another stream at the end are passed over, even when
+            // they take up more than one chunk of the search
+            let data = vec![0; 255 * 250];
+            for sequence_no in 0..2 {
+                input.extend(test_stream::ogg_page(
+                    0,
+                    1 << 40,
+                    config.serial + 1,
+                    sequence_no,
+                    &[255; 250],
+                    &data,
+                ));
+            }
+            let mut decoder = VorbisDecoder::new(Cursor::new(input)).unwrap();
+            let info = decoder.stream_info().unwrap();
+            assert_eq!(info.total_samples, total_samples);
+            assert_eq!(info.duration, expected.duration);
+        }
+
+        // Without any audio the bitrate comes from the identification header
+        let config = StreamConfig {
+            blocks: Vec::new(),
+            ..Default::default()
+        };
+        let (input, _) = test_stream::build(&config);
+        let mut decoder = VorbisDecoder::new(Cursor::new(input.clone())).unwrap();
+        let expected = StreamInfo {
+            total_samples: 0,
+            duration: Duration::ZERO,
+            bitrate: Some(128_000),
+        };
+        assert_eq!(decoder.stream_info().unwrap(), expected);
+        assert_eq!(stream_info(Cursor::new(input)).unwrap(), expected);
+
+        let input = include_bytes!("../bin/beep3.ogg");
+        let info = stream_info(Cursor::new(&input[..])).unwrap();
+        assert_eq!(info.total_samples, 441000);
+        let mut decoder = VorbisDecoder::new(Cursor::new(&input[..])).unwrap();
+        assert_eq!(decoder.stream_info().unwrap(), info);
+
+        // Durations too long for `Duration` and bitrates too high for a u32
+        // are saturated
+        let (input, _) = test_stream::build(&StreamConfig::default());
+        let total_samples = stream_info(Cursor::new(input)).unwrap().total_samples;
+        let config = StreamConfig {
+            sample_rate: 1,
+            start_granule: u64::MAX - 1 - total_samples,
+            ..Default::default()
+        };
+        let (input, _) = test_stream::build(&config);
+        let info = stream_info(Cursor::new(input)).unwrap();
+        assert_eq!(info.duration, Duration::MAX);
+        assert_eq!(info.bitrate, Some(0));
+        let config = StreamConfig {
+            sample_rate: u32::MAX,
+            ..Default::default()
+        };
+        let (input, _) = test_stream::build(&config);
+        let info = stream_info(Cursor::new(input)).unwrap();
+        assert_eq!(info.bitrate, Some(u32::MAX));
+    }
+
+    #[test]
+    fn test_decoder_chained() {
+        fn decode(decoder: &mut VorbisDecoder<Cursor<Vec<u8>>>) -> (Vec<Vec<f32>>, bool) {
+            let channels = decoder.id_header().audio_channels() as usize;
+            let mut pcm = vec![Vec::new(); channels];
+            loop {
+                match decoder.next_event().unwrap() {
+                    Some(DecoderEvent::Pcm(packet_pcm)) => {
+                        for (channel, packet_channel) in pcm.iter_mut().zip(packet_pcm) {
+                            channel.extend(packet_channel);
+                        }
+                    }
+                    Some(DecoderEvent::StreamChanged { .. }) => return (pcm, true),
+                    Some(DecoderEvent::PacketsLost) => panic!("packets lost"),
+                    None => return (pcm, false),
+                }
+            }
+        }
+
+        let first = StreamConfig {
+            end_trim: 10,
+            ..Default::default()
+        };
+        let second = StreamConfig {
+            channels: 2,
+            sample_rate: 22050,
+            serial: 0x0BAD_CAFE,
+            seed: 2,
+            start_granule: 500,
+            ..Default::default()
+        };
+        let (first_input, _) = test_stream::build(&first);
+        let (second_input, _) = test_stream::build(&second);
+        let (first_pcm, _) =
+            decode(&mut VorbisDecoder::new(Cursor::new(first_input.clone())).unwrap());
+        let (second_pcm, _) =
+            decode(&mut VorbisDecoder::new(Cursor::new(second_input.clone())).unwrap());
+
+        let input = [first_input.clone(), second_input].concat();
+        let mut decoder = VorbisDecoder::new(Cursor::new(input.clone())).unwrap();
+        let info = decoder.stream_info().unwrap();
+        assert_eq!(info.total_samples, first_pcm[0].len() as u64);
+        assert_eq!(decode(&mut decoder), (first_pcm.clone(), true));
+        assert_eq!(decoder.id_header().audio_channels(), 2);
+        assert_eq!(decoder.id_header().audio_sample_rate(), 22050);
+        assert_eq!(decoder.first_sample_offset(), 500);
+        assert_eq!(decoder.samples_decoded(), 0);
+        assert_eq!(decode(&mut decoder), (second_pcm.clone(), false));
+        assert_eq!(decoder.samples_decoded(), second_pcm[0].len() as u64);
+
+        // Seeking stays within the current stream
+        let mut decoder = VorbisDecoder::new(Cursor::new(input.clone())).unwrap();
+        decoder.seek_to_sample(300).unwrap();
+        assert_eq!(
+            decode(&mut decoder),
+            (vec![first_pcm[0][300..].to_vec()], true)
+        );
+        decoder.seek_to_sample(300).unwrap();
+        let expected: Vec<Vec<f32>> = second_pcm.iter().map(|c| c[300..].to_vec()).collect();
+        assert_eq!(decode(&mut decoder), (expected, false));
+
+        // The event carries the new stream's details
+        let mut decoder = VorbisDecoder::new(Cursor::new(input.clone())).unwrap();
+        let event = loop {
+            match decoder.next_event().unwrap().unwrap() {
+                DecoderEvent::Pcm(_) => (),
+                event => break event,
+            }
+        };
+        match event {
+            DecoderEvent::StreamChanged {
+                channels,
+                sample_rate,
+                comment_header,
+            } => {
+                assert_eq!((channels, sample_rate), (2, 22050));
+                assert_eq!(comment_header.vendor_string(), "test_stream");
+                assert_eq!(comment_header.user_comments()[0].comment(), "TITLE=Noise");
+            }
+            event => panic!("{:?}", event),
+        }
+
+        // Plain decoding stops at the change until it's been reported
+        let mut decoder = VorbisDecoder::new(Cursor::new(input)).unwrap();
+        let mut pcm = Vec::new();
+        let err = loop {
+            match decoder.next_pcm() {
+                Ok(Some(packet_pcm)) => pcm.extend_from_slice(&packet_pcm[0]),
+                Ok(None) => panic!("no error at the change"),
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(
+            err,
+            VorbisError::FormatChanged {
+                channels: 2,
+                sample_rate: 22050
+            }
+        ));
+        assert_eq!(pcm, first_pcm[0]);
+        assert!(decoder.next_pcm().is_err());
+        assert!(matches!(
+            decoder.next_event().unwrap(),
+            Some(DecoderEvent::StreamChanged { .. })
+        ));
+        let mut pcm = vec![Vec::new(); 2];
+        while let Some(packet_pcm) = decoder.next_pcm().unwrap() {
+            for (channel, packet_channel) in pcm.iter_mut().zip(packet_pcm) {
+                channel.extend(packet_channel);
+            }
+        }
+        assert_eq!(pcm, second_pcm);
+
+        // But carries on through streams of the same format
+        let second = StreamConfig {
+            seed: 2,
+            serial: 0x0BAD_CAFE,
+            ..Default::default()
+        };
+        let (second_input, _) = test_stream::build(&second);
+        let (second_pcm, _) =
+            decode(&mut VorbisDecoder::new(Cursor::new(second_input.clone())).unwrap());
+        let input = [first_input, second_input].concat();
+        let mut decoder = VorbisDecoder::new(Cursor::new(input)).unwrap();
+        let mut pcm = Vec::new();
+        while let Some(packet_pcm) = decoder.next_pcm().unwrap() {
+            pcm.extend_from_slice(&packet_pcm[0]);
+        }
+        assert_eq!(pcm, [&first_pcm[0][..], &second_pcm[0][..]].concat());
+    }
+
+    #[test]
+    fn test_decoder_multiplexed() {
+        let config = StreamConfig {
+            channels: 2,
+            ..Default::default()
+        };
+        let (input, _) = test_stream::build(&config);
+        let mut decoder = VorbisDecoder::new(Cursor::new(input.clone())).unwrap();
+        let mut linear = Vec::new();
+        while let Some(packet_pcm) = decoder.next_pcm().unwrap() {
+            linear.push(packet_pcm);
+        }
+
+        // Split the stream into pages and interleave them with the pages of
+        // another stream, whose first page comes first
+        let mut pages = Vec::new();
+        let mut position = 0;
+        while position < input.len() {
+            let header_len = 27 + input[position + 26] as usize;
+            let data_len: usize = input[position + 27..position + header_len]
+                .iter()
+                .map(|b| *b as usize)
+                .sum();
+            pages.push(&input[position..position + header_len + data_len]);
+            position += header_len + data_len;
+        }
+        let serial = 0x600D_F00D;
+        let mut muxed = test_stream::ogg_page(0x02, 0, serial, 0, &[7], b"\x80theora");
+        for (i, page) in pages.iter().enumerate() {
+            muxed.extend_from_slice(page);
+            let data = vec![i as u8; 100];
+            let flag = if i + 1 == pages.len() { 0x04 } else { 0x00 };
+            muxed.extend(test_stream::ogg_page(
+                flag,
+                i as u64,
+                serial,
+                i as u32 + 1,
+                &[100],
+                &data,
+            ));
+        }
+
+        // The other stream's packets are only kept when asked for
+        let mut decoder = VorbisDecoder::new(Cursor::new(muxed.clone())).unwrap();
+        while decoder.next_pcm().unwrap().is_some() {}
+        assert_eq!(decoder.next_other_packet(serial), None);
+
+        let mut decoder =
+            VorbisDecoder::with_page_error_policy(Cursor::new(muxed), PageErrorPolicy::Strict)
+                .unwrap();
+        decoder.set_keep_other_packets(true);
+        assert_eq!(decoder.id_header().audio_channels(), 2);
+        let mut pcm = Vec::new();
+        while let Some(packet_pcm) = decoder.next_pcm().unwrap() {
+            pcm.push(packet_pcm);
+        }
+        assert_eq!(pcm, linear);
+
+        let streams: Vec<_> = decoder
+            .other_streams()
+            .map(|stream| (stream.serial(), stream.first_packet()))
+            .collect();
+        assert_eq!(streams, [(serial, Some(&b"\x80theora"[..]))]);
+
+        // The pages up to the first audio page with a granule position were
+        // read by `with_page_error_policy`, before the packets were kept
+        for i in 5..pages.len() {
+            assert_eq!(
+                decoder.next_other_packet(serial).unwrap(),
+                vec![i as u8; 100]
+            );
+        }
+        assert_eq!(decoder.next_other_packet(serial), None);
+        assert_eq!(decoder.next_other_packet(serial + 1), None);
+
+        // Seeking and the stream length only go by the Vorbis pages
+        let len = linear
+            .iter()
+            .map(|packet_pcm| packet_pcm[0].len())
+            .sum::<usize>();
+        assert_eq!(decoder.stream_info().unwrap().total_samples, len as u64);
+        decoder.seek_to_sample(500).unwrap();
+        let mut pcm = Vec::new();
+        while let Some(packet_pcm) = decoder.next_pcm().unwrap() {
+            pcm.extend_from_slice(&packet_pcm[1]);
+        }
+        let linear: Vec<f32> = linear
+            .into_iter()
+            .flat_map(|packet_pcm| packet_pcm[1].clone())
+            .collect();
+        assert_eq!(pcm, linear[500..]);
+    }
+
+    #[test]
+    fn test_decoder_next_pcm_interleaved() {
+        let config = StreamConfig::default();
+        let (input, _) = test_stream::build(&config);
+        let mut planar = VorbisDecoder::new(Cursor::new(input.clone())).unwrap();
+        let mut interleaved = VorbisDecoder::new(Cursor::new(input)).unwrap();
+        while let Some(pcm) = planar.next_pcm().unwrap() {
+            let samples = interleaved.next_pcm_interleaved().unwrap().unwrap();
+            assert_eq!(samples, pcm[0]);
+        }
+        assert_eq!(interleaved.next_pcm_interleaved().unwrap(), None);
+    }
+}
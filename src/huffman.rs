@@ -1,113 +1,242 @@
-#[derive(Debug, Default, PartialEq)]
+use crate::bit_reader::PacketBitReader;
+use bitstream_io::BitRead;
+use thiserror::Error;
+
+/// The longest prefix that is decoded with a single table lookup
+const MAX_LOOKUP_BITS: u8 = 10;
+
+/// A Huffman tree flattened into a list of nodes, each holding its two
+/// branches. The root is always node 0. Decoding peeks at the next
+/// `lookup_bits` bits and looks them up in a table, which gives the entry for
+/// any codeword that isn't longer than that. Longer codewords carry on down
+/// the tree, one bit at a time, from the node the table points to.
+#[derive(Debug, PartialEq)]
 pub struct HuffmanTree {
-    root: HuffmanNode,
+    nodes: Vec<[HuffmanBranch; 2]>,
+    lookup_bits: u8,
+    lookup_table: Vec<(HuffmanBranch, u8)>, // Where the bits lead and how many of them that takes
 }
 
 impl HuffmanTree {
     pub fn new() -> Self {
-        Self::default()
-    }
-
-    pub fn add_node(&mut self, length: u8, value: u32) {
-        let done = self.root.add_node(length, value);
-        assert!(done);
-    }
-}
-
-#[derive(Debug, Default, PartialEq)]
-struct HuffmanNode {
-    left: Option<Box<HuffmanNode>>,
-    right: Option<Box<HuffmanNode>>,
-    value: Option<u32>,
-}
-
-impl HuffmanNode {
-    fn new() -> Self {
-        Self::default()
-    }
-
-    fn with_value(value: u32) -> Self {
         Self {
-            value: Some(value),
-            ..Self::default()
+            nodes: vec![[HuffmanBranch::Empty; 2]],
+            lookup_bits: 0,
+            lookup_table: vec![(HuffmanBranch::Empty, 0)],
         }
     }
 
-    fn is_leaf(&self) -> bool {
-        self.value.is_none() == false
-    }
-
-    fn add_node(&mut self, length: u8, value: u32) -> bool {
-        // TODO: make this a stack-based traversal (instead of recursive) operation on HuffmanTree? Depends on performance benefits.
-        match length {
-            0 => panic!("Invalid Huffman node depth == 0"),
-            1 => {
-                // We are at the correct depth
+    /// Build the tree for a codebook from its codeword lengths, where unused
+    /// entries have no length. Every branch of the tree must lead somewhere,
+    /// except when there is only one used entry: its codeword is all zeros but
+    /// any bits of that length decode to it.
+    pub fn from_lengths(codeword_lengths: &[Option<u8>]) -> Result<Self, HuffmanError> {
+        // Each entry takes the lowest free codeword of its length, the same
+        // way libvorbis assigns them. The lowest free codeword of every length
+        // is kept up to date as they're taken, most significant bit first, so
+        // that each one can be found without searching the tree.
+        let mut next_codeword = [0u64; 33];
+        let mut tree = Self::new();
+        for (value, length) in codeword_lengths.iter().enumerate() {
+            let length = match *length {
+                None => continue,
+                Some(0) => return Err(HuffmanError::ZeroLength(value as u32)),
+                Some(len) if len > 32 => return Err(HuffmanError::TooLong(value as u32)),
+                Some(len) => len as usize,
+            };
+            let codeword = next_codeword[length];
+            if codeword >> length != 0 {
+                return Err(HuffmanError::Overspecified);
+            }
+            tree.add_codeword(codeword, length, value as u32);
 
-                // Add the new node on the left, if there's room
-                if self.left.is_none() == true {
-                    self.left = Some(Box::new(Self::with_value(value)));
-                    return true;
+            // Shorter codewords on the way to this one are no longer free, so
+            // move them along to the next branch over
+            for j in (1..=length).rev() {
+                if next_codeword[j] & 1 == 1 {
+                    next_codeword[j] = if j == 1 {
+                        next_codeword[1] + 1
+                    } else {
+                        next_codeword[j - 1] << 1
+                    };
+                    break;
                 }
+                next_codeword[j] += 1;
+            }
 
-                // Add the new node on the right, if there's room
-                if self.right.is_none() == true {
-                    self.right = Some(Box::new(Self::with_value(value)));
-                    return true;
+            // Neither are longer ones below this one, so move them below the
+            // next free codeword of this length instead
+            let mut taken = codeword;
+            for j in length + 1..next_codeword.len() {
+                if next_codeword[j] >> 1 != taken {
+                    break;
                 }
+                taken = next_codeword[j];
+                next_codeword[j] = next_codeword[j - 1] << 1;
+            }
+        }
+
+        let used_entries = codeword_lengths.iter().flatten().count();
+        if used_entries == 1 {
+            // The tree is a single path of zeros, so make the ones follow it too
+            for node in tree.nodes.iter_mut() {
+                node[1] = node[0];
             }
-            _ => {
-                // Look deeper
+        } else if used_entries > 1 {
+            // Once every codeword of a length is taken, the next free one
+            // would need another bit
+            let underspecified = next_codeword
+                .iter()
+                .enumerate()
+                .skip(1)
+                .any(|(length, codeword)| codeword & ((1 << length) - 1) != 0);
+            if underspecified == true {
+                return Err(HuffmanError::Underspecified);
+            }
+        }
 
-                // Try going left first. If needed, create a new node there first.
-                if self.left.is_none() == true {
-                    self.left = Some(Box::new(Self::new()));
-                }
+        let longest = codeword_lengths.iter().flatten().max().copied();
+        tree.build_lookup_table(longest.unwrap_or(0).min(MAX_LOOKUP_BITS));
+        Ok(tree)
+    }
 
-                // Go left if the left node exists and is not a leaf
-                if let Some(ref mut node) = &mut self.left {
-                    if node.is_leaf() == false {
-                        let done = node.add_node(length - 1, value);
-                        if done == true {
-                            return true;
-                        }
-                    }
+    /// Place a leaf at the end of the path given by the `length` bits of
+    /// `codeword`, most significant first, creating nodes along the way.
+    fn add_codeword(&mut self, codeword: u64, length: usize, value: u32) {
+        let mut node = 0;
+        for depth in (1..length).rev() {
+            let side = (codeword >> depth) as usize & 1;
+            node = match self.nodes[node][side] {
+                HuffmanBranch::Node(child) => child as usize,
+                _ => {
+                    let child = self.nodes.len();
+                    self.nodes.push([HuffmanBranch::Empty; 2]);
+                    self.nodes[node][side] = HuffmanBranch::Node(child as u32);
+                    child
                 }
+            };
+        }
+        self.nodes[node][codeword as usize & 1] = HuffmanBranch::Leaf(value);
+    }
 
-                // Try going right. If needed, create a new node there first.
-                if self.right.is_none() == true {
-                    self.right = Some(Box::new(Self::default()));
-                }
+    /// Record where each possible sequence of the next `bits` bits leads to,
+    /// and how many of them it takes to get there. Bits are indexed in the
+    /// order they're read from the stream, so the first bit is the least
+    /// significant, and a codeword shorter than `bits` fills every entry
+    /// that starts with it.
+    fn build_lookup_table(&mut self, bits: u8) {
+        self.lookup_bits = bits;
+        self.lookup_table = vec![(HuffmanBranch::Empty, 0); 1 << bits];
+        if bits == 0 {
+            return;
+        }
 
-                // Go right if the right node exists and is not a leaf
-                if let Some(ref mut node) = &mut self.right {
-                    if node.is_leaf() == false {
-                        let done = node.add_node(length - 1, value);
-                        if done == true {
-                            return true;
+        let mut stack = vec![(0, 0, 0)]; // Node, bits so far, and its depth
+        while let Some((node, code, depth)) = stack.pop() {
+            for (side, branch) in self.nodes[node].iter().enumerate() {
+                let code = code | (side << depth);
+                match *branch {
+                    HuffmanBranch::Node(child) if depth + 1 < bits => {
+                        stack.push((child as usize, code, depth + 1))
+                    }
+                    _ => {
+                        for index in (code..1 << bits).step_by(1 << (depth + 1)) {
+                            self.lookup_table[index] = (*branch, depth + 1);
                         }
                     }
                 }
             }
         }
+    }
 
-        // There wasn't room here or at any node lower in the tree. Go back up
-        // the tree and look for another node at this depth on another branch.
-        false
+    /// Look up the next bits in the table, then if the codeword is longer
+    /// than that, read the rest one bit at a time, following the tree until a
+    /// leaf is reached. Returns the entry number stored in that leaf.
+    pub fn decode_entry(&self, reader: &mut PacketBitReader) -> Result<u32, HuffmanError> {
+        let index = reader.peek(self.lookup_bits as u32);
+        let (mut branch, length) = self.lookup_table[index as usize];
+        reader.consume(length as u32)?;
+        while let HuffmanBranch::Node(node) = branch {
+            let bit: bool = reader.read_bit()?;
+            branch = self.nodes[node as usize][bit as usize];
+        }
+        match branch {
+            HuffmanBranch::Leaf(value) => Ok(value),
+            _ => Err(HuffmanError::UnpopulatedBranch),
+        }
     }
 }
 
+impl Default for HuffmanTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum HuffmanBranch {
+    Empty,
+    Node(u32),
+    Leaf(u32),
+}
+
+#[derive(Debug, Error)]
+pub enum HuffmanError {
+    #[error("Codeword length of zero for entry {0}")]
+    ZeroLength(u32),
+
+    #[error("Codeword longer than 32 bits for entry {0}")]
+    TooLong(u32),
+
+    #[error("Codeword leads to an unpopulated branch")]
+    UnpopulatedBranch,
+
+    #[error("More codewords than fit in the tree")]
+    Overspecified,
+
+    #[error("Not enough codewords to fill the tree")]
+    Underspecified,
+
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::{test_stream::Rng, VorbisDecoder};
+    use bitstream_io::{BitReader, LittleEndian};
+    use std::{
+        io::Cursor,
+        time::{Duration, Instant},
+    };
 
-    #[test]
-    fn test_add_node() {
-        let mut tree = HuffmanTree::default();
-        let lengths = vec![2, 4, 4, 4, 4, 2, 3, 3];
-        for (i, length) in lengths.into_iter().enumerate() {
-            tree.add_node(length, i as u32);
+    // Codebook 0 of the Frampton stream, the only one of its codebooks kept
+    // here since the stream itself isn't in the repository
+    const FRAMPTON_LENGTHS: [u8; 8] = [1, 3, 4, 7, 2, 5, 6, 7];
+
+    fn branch_at(tree: &HuffmanTree, path: &[usize]) -> HuffmanBranch {
+        let mut branch = HuffmanBranch::Node(0);
+        for &bit in path {
+            match branch {
+                HuffmanBranch::Node(node) => branch = tree.nodes[node as usize][bit],
+                x => panic!("Unexpected branch: {:?}", x),
+            }
         }
+        branch
+    }
+
+    fn is_node(branch: HuffmanBranch) -> bool {
+        matches!(branch, HuffmanBranch::Node(_))
+    }
+
+    fn lengths(lengths: &[u8]) -> Vec<Option<u8>> {
+        lengths.iter().copied().map(Some).collect()
+    }
+
+    #[test]
+    fn test_from_lengths() {
+        let tree = HuffmanTree::from_lengths(&lengths(&[2, 4, 4, 4, 4, 2, 3, 3])).unwrap();
 
         /*
             Tree should look like this:
@@ -120,46 +249,368 @@ mod test {
             entry 6: length 3 codeword 110
             entry 7: length 3 codeword 111
         */
-        let l = tree.root.left.unwrap();
-        assert_eq!(l.value, None);
+        assert!(is_node(branch_at(&tree, &[0])));
+        assert_eq!(branch_at(&tree, &[0, 0]), HuffmanBranch::Leaf(0));
+        assert!(is_node(branch_at(&tree, &[0, 1])));
+        assert!(is_node(branch_at(&tree, &[0, 1, 0])));
+        assert_eq!(branch_at(&tree, &[0, 1, 0, 0]), HuffmanBranch::Leaf(1));
+        assert_eq!(branch_at(&tree, &[0, 1, 0, 1]), HuffmanBranch::Leaf(2));
+        assert!(is_node(branch_at(&tree, &[0, 1, 1])));
+        assert_eq!(branch_at(&tree, &[0, 1, 1, 0]), HuffmanBranch::Leaf(3));
+        assert_eq!(branch_at(&tree, &[0, 1, 1, 1]), HuffmanBranch::Leaf(4));
+        assert!(is_node(branch_at(&tree, &[1])));
+        assert_eq!(branch_at(&tree, &[1, 0]), HuffmanBranch::Leaf(5));
+        assert!(is_node(branch_at(&tree, &[1, 1])));
+        assert_eq!(branch_at(&tree, &[1, 1, 0]), HuffmanBranch::Leaf(6));
+        assert_eq!(branch_at(&tree, &[1, 1, 1]), HuffmanBranch::Leaf(7));
+        assert_eq!(tree.nodes.len(), 7);
+    }
+
+    #[test]
+    fn test_lookup_table() {
+        // Covers the longest codeword, indexed with the first bit read as the
+        // least significant
+        let tree = HuffmanTree::from_lengths(&lengths(&[2, 4, 4, 4, 4, 2, 3, 3])).unwrap();
+        assert_eq!(tree.lookup_bits, 4);
+        for index in [0b0000, 0b0100, 0b1000, 0b1100] {
+            assert_eq!(tree.lookup_table[index], (HuffmanBranch::Leaf(0), 2));
+        }
+        assert_eq!(tree.lookup_table[0b1010], (HuffmanBranch::Leaf(2), 4));
+        assert_eq!(tree.lookup_table[0b0001], (HuffmanBranch::Leaf(5), 2));
+        assert_eq!(tree.lookup_table[0b0111], (HuffmanBranch::Leaf(7), 3));
+        assert_eq!(tree.lookup_table[0b1111], (HuffmanBranch::Leaf(7), 3));
+
+        // Longer codewords continue from a node
+        let mut long = vec![1];
+        long.extend([12; 2048].iter());
+        let tree = HuffmanTree::from_lengths(&lengths(&long)).unwrap();
+        assert_eq!(tree.lookup_bits, MAX_LOOKUP_BITS);
+        assert_eq!(tree.lookup_table[0b10], (HuffmanBranch::Leaf(0), 1));
+        let (branch, length) = tree.lookup_table[0b1];
+        assert_eq!(branch, branch_at(&tree, &[1, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+        assert!(is_node(branch));
+        assert_eq!(length, MAX_LOOKUP_BITS);
+
+        // Unused entries don't count towards the longest codeword
+        let tree = HuffmanTree::from_lengths(&[None, Some(1), Some(1), None]).unwrap();
+        assert_eq!(tree.lookup_bits, 1);
+        let tree = HuffmanTree::from_lengths(&[None, None]).unwrap();
+        assert_eq!(tree.lookup_bits, 0);
+    }
+
+    #[test]
+    fn test_decode_entry() {
+        let tree = HuffmanTree::from_lengths(&lengths(&[2, 4, 4, 4, 4, 2, 3, 3])).unwrap();
 
-        let ll = l.left.unwrap();
-        assert_eq!(*ll, HuffmanNode::with_value(0));
+        // Codewords 00, 10, 0110, 111, 00, 00 and then a partial codeword
+        let input = [0b01100100, 0b00000111];
+        let mut reader = PacketBitReader::new(&input);
+        assert_eq!(tree.decode_entry(&mut reader).unwrap(), 0);
+        assert_eq!(tree.decode_entry(&mut reader).unwrap(), 5);
+        assert_eq!(tree.decode_entry(&mut reader).unwrap(), 3);
+        assert_eq!(tree.decode_entry(&mut reader).unwrap(), 7);
+        assert_eq!(tree.decode_entry(&mut reader).unwrap(), 0);
+        assert_eq!(tree.decode_entry(&mut reader).unwrap(), 0);
+        match tree.decode_entry(&mut reader).unwrap_err() {
+            HuffmanError::IOError(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+
+        // Codewords longer than the table, 0 then 1 followed by 11 zeros
+        let mut long = vec![1];
+        long.extend([12; 2048].iter());
+        let tree = HuffmanTree::from_lengths(&lengths(&long)).unwrap();
+        let input = [0b00000010, 0b00000000, 0b00000100];
+        let mut reader = PacketBitReader::new(&input);
+        assert_eq!(tree.decode_entry(&mut reader).unwrap(), 0);
+        assert_eq!(tree.decode_entry(&mut reader).unwrap(), 1);
+        for _ in 0..5 {
+            assert_eq!(tree.decode_entry(&mut reader).unwrap(), 0);
+        }
+        match tree.decode_entry(&mut reader).unwrap_err() {
+            HuffmanError::IOError(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+
+        // No entries are used
+        let tree = HuffmanTree::from_lengths(&[None]).unwrap();
+        let input = [0b00000000];
+        let mut reader = PacketBitReader::new(&input);
+        let err = tree.decode_entry(&mut reader).unwrap_err();
+        assert!(matches!(err, HuffmanError::UnpopulatedBranch));
+    }
+
+    #[test]
+    fn test_from_lengths_single_entry() {
+        // Any bit decodes to the only entry
+        let tree = HuffmanTree::from_lengths(&[None, Some(1), None]).unwrap();
+        let input = [0b00000010];
+        let mut reader = PacketBitReader::new(&input);
+        assert_eq!(tree.decode_entry(&mut reader).unwrap(), 1);
+        assert_eq!(tree.decode_entry(&mut reader).unwrap(), 1);
+
+        // Longer codewords still use up all of their bits
+        let tree = HuffmanTree::from_lengths(&[Some(3)]).unwrap();
+        let input = [0b11010110];
+        let mut reader = PacketBitReader::new(&input);
+        assert_eq!(tree.decode_entry(&mut reader).unwrap(), 0);
+        assert_eq!(tree.decode_entry(&mut reader).unwrap(), 0);
+        let bits: u8 = reader.read(2).unwrap();
+        assert_eq!(bits, 0b11);
+    }
+
+    #[test]
+    fn test_from_lengths_invalid() {
+        // Overspecified
+        let err = HuffmanTree::from_lengths(&lengths(&[2, 4, 4, 4, 4, 2, 3, 3, 3])).unwrap_err();
+        assert!(matches!(err, HuffmanError::Overspecified));
+        let err = HuffmanTree::from_lengths(&lengths(&[1, 1, 1])).unwrap_err();
+        assert!(matches!(err, HuffmanError::Overspecified));
+        let err = HuffmanTree::from_lengths(&lengths(&[1, 1, 32])).unwrap_err();
+        assert!(matches!(err, HuffmanError::Overspecified));
+
+        // Underspecified
+        let err = HuffmanTree::from_lengths(&lengths(&[2, 4, 4, 4, 4, 2, 3])).unwrap_err();
+        assert!(matches!(err, HuffmanError::Underspecified));
+        let err = HuffmanTree::from_lengths(&lengths(&[2, 4, 4, 4, 2, 3, 3])).unwrap_err();
+        assert!(matches!(err, HuffmanError::Underspecified));
+        let err = HuffmanTree::from_lengths(&[Some(2), None, Some(2)]).unwrap_err();
+        assert!(matches!(err, HuffmanError::Underspecified));
+        let err = HuffmanTree::from_lengths(&lengths(&[1, 32])).unwrap_err();
+        assert!(matches!(err, HuffmanError::Underspecified));
+
+        // Zero length
+        let err = HuffmanTree::from_lengths(&lengths(&[1, 0, 1])).unwrap_err();
+        assert!(matches!(err, HuffmanError::ZeroLength(1)));
+
+        // Too long
+        let err = HuffmanTree::from_lengths(&lengths(&[1, 33, 1])).unwrap_err();
+        assert!(matches!(err, HuffmanError::TooLong(1)));
+    }
 
-        let lr = l.right.unwrap();
-        assert_eq!(lr.value, None);
+    /// The original boxed tree, kept as it was to compare against
+    #[derive(Debug, Default, PartialEq)]
+    struct HuffmanNode {
+        left: Option<Box<HuffmanNode>>,
+        right: Option<Box<HuffmanNode>>,
+        value: Option<u32>,
+    }
 
-        let lrl = lr.left.unwrap();
-        assert_eq!(lrl.value, None);
+    impl HuffmanNode {
+        fn new() -> Self {
+            Self::default()
+        }
 
-        let lrll = lrl.left.unwrap();
-        assert_eq!(*lrll, HuffmanNode::with_value(1));
+        fn with_value(value: u32) -> Self {
+            Self {
+                value: Some(value),
+                ..Self::default()
+            }
+        }
 
-        let lrlr = lrl.right.unwrap();
-        assert_eq!(*lrlr, HuffmanNode::with_value(2));
+        fn is_leaf(&self) -> bool {
+            self.value.is_none() == false
+        }
 
-        let lrr = lr.right.unwrap();
-        assert_eq!(lrr.value, None);
+        fn add_node(&mut self, length: u8, value: u32) -> bool {
+            match length {
+                0 => panic!("Invalid Huffman node depth == 0"),
+                1 => {
+                    // We are at the correct depth
 
-        let lrrl = lrr.left.unwrap();
-        assert_eq!(*lrrl, HuffmanNode::with_value(3));
+                    // Add the new node on the left, if there's room
+                    if self.left.is_none() == true {
+                        self.left = Some(Box::new(Self::with_value(value)));
+                        return true;
+                    }
 
-        let lrrr = lrr.right.unwrap();
-        assert_eq!(*lrrr, HuffmanNode::with_value(4));
+                    // Add the new node on the right, if there's room
+                    if self.right.is_none() == true {
+                        self.right = Some(Box::new(Self::with_value(value)));
+                        return true;
+                    }
+                }
+                _ => {
+                    // Look deeper
 
-        let r = tree.root.right.unwrap();
-        assert_eq!(r.value, None);
+                    // Try going left first. If needed, create a new node there first.
+                    if self.left.is_none() == true {
+                        self.left = Some(Box::new(Self::new()));
+                    }
 
-        let rl = r.left.unwrap();
-        assert_eq!(*rl, HuffmanNode::with_value(5));
+                    // Go left if the left node exists and is not a leaf
+                    if let Some(ref mut node) = &mut self.left {
+                        if node.is_leaf() == false {
+                            let done = node.add_node(length - 1, value);
+                            if done == true {
+                                return true;
+                            }
+                        }
+                    }
 
-        let rr = r.right.unwrap();
-        assert_eq!(rr.value, None);
+                    // Try going right. If needed, create a new node there first.
+                    if self.right.is_none() == true {
+                        self.right = Some(Box::new(Self::default()));
+                    }
 
-        let rrl = rr.left.unwrap();
-        assert_eq!(*rrl, HuffmanNode::with_value(6));
+                    // Go right if the right node exists and is not a leaf
+                    if let Some(ref mut node) = &mut self.right {
+                        if node.is_leaf() == false {
+                            let done = node.add_node(length - 1, value);
+                            if done == true {
+                                return true;
+                            }
+                        }
+                    }
+                }
+            }
 
-        let rrr = rr.right.unwrap();
-        assert_eq!(*rrr, HuffmanNode::with_value(7));
+            // There wasn't room here or at any node lower in the tree. Go back up
+            // the tree and look for another node at this depth on another branch.
+            false
+        }
+
+        fn decode_entry<R, E>(&self, reader: &mut BitReader<R, E>) -> Result<u32, HuffmanError>
+        where
+            R: std::io::Read,
+            E: bitstream_io::Endianness,
+        {
+            let mut node = self;
+            loop {
+                let bit: bool = reader.read_bit()?;
+                let next = if bit == true { &node.right } else { &node.left };
+                node = next.as_ref().ok_or(HuffmanError::UnpopulatedBranch)?;
+                if let Some(value) = node.value {
+                    return Ok(value);
+                }
+            }
+        }
+    }
+
+    /// Every codebook in `bin/plop.ogg`, Frampton codebook 0 and some made
+    /// up ones. Codebooks with a single entry are left out since the original
+    /// tree doesn't decode them.
+    fn test_codebooks() -> Vec<Vec<Option<u8>>> {
+        let mut codebooks = vec![
+            lengths(&FRAMPTON_LENGTHS),
+            lengths(&[8; 256]),
+            lengths(&(1..=20).chain([20]).collect::<Vec<u8>>()),
+        ];
+        let input = include_bytes!("../bin/plop.ogg");
+        let decoder = VorbisDecoder::new(Cursor::new(&input[..])).unwrap();
+        for codebook in decoder.setup_header().codebooks() {
+            let lengths = codebook.codeword_lengths();
+            if lengths.iter().flatten().count() > 1 {
+                codebooks.push(lengths.to_vec());
+            }
+        }
+        codebooks
+    }
+
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut rng = Rng::new(0x5EED);
+        (0..len).map(|_| rng.next() as u8).collect()
+    }
+
+    struct Comparison {
+        entries: usize,
+        codewords: usize,
+        lookup_bits: u8,
+        build_times: [Duration; 2],
+        decode_times: [Duration; 2],
+    }
+
+    /// Build each tree both ways and decode random data with them, checking
+    /// that they agree. Returns the fastest of `runs` times for each.
+    fn compare_to_original(input_len: usize, runs: usize) -> Vec<Comparison> {
+        let input = random_bytes(input_len);
+        let mut comparisons = Vec::new();
+        for lengths in test_codebooks() {
+            let mut build_times = [Duration::MAX; 2];
+            let mut decode_times = [Duration::MAX; 2];
+            let mut codewords = 0;
+            let mut lookup_bits = 0;
+            for _ in 0..runs {
+                let start = Instant::now();
+                let mut original = HuffmanNode::new();
+                for (i, length) in lengths.iter().enumerate() {
+                    if let Some(length) = *length {
+                        assert!(original.add_node(length, i as u32));
+                    }
+                }
+                build_times[0] = build_times[0].min(start.elapsed());
+                let start = Instant::now();
+                let tree = HuffmanTree::from_lengths(&lengths).unwrap();
+                build_times[1] = build_times[1].min(start.elapsed());
+                lookup_bits = tree.lookup_bits;
+
+                let mut cursor = Cursor::new(&input);
+                let mut reader = BitReader::endian(&mut cursor, LittleEndian);
+                let mut expected = Vec::new();
+                let start = Instant::now();
+                while let Ok(entry) = original.decode_entry(&mut reader) {
+                    expected.push(entry);
+                }
+                decode_times[0] = decode_times[0].min(start.elapsed());
+
+                let mut reader = PacketBitReader::new(&input);
+                let mut entries = Vec::new();
+                let start = Instant::now();
+                while let Ok(entry) = tree.decode_entry(&mut reader) {
+                    entries.push(entry);
+                }
+                decode_times[1] = decode_times[1].min(start.elapsed());
+
+                assert_eq!(entries, expected);
+                codewords = entries.len();
+            }
+            comparisons.push(Comparison {
+                entries: lengths.len(),
+                codewords,
+                lookup_bits,
+                build_times,
+                decode_times,
+            });
+        }
+        comparisons
+    }
+
+    #[test]
+    fn test_decode_entry_matches_original_tree() {
+        compare_to_original(4096, 1);
+    }
+
+    /// Compare building and decoding with the original tree over
+    /// `test_codebooks`, which are mostly plop's since only the first
+    /// Frampton codebook is at hand. Run with
+    /// `cargo test --release -- --ignored --nocapture bench_huffman_tree`
+    #[test]
+    #[ignore]
+    fn bench_huffman_tree() {
+        let comparisons = compare_to_original(1 << 20, 20);
+        println!(
+            "entries  lookup  build (original / table)  decode per codeword (original / table)"
+        );
+        for c in comparisons.iter() {
+            println!(
+                "{:7}  {:6}  {:8.1} / {:8.1} us        {:6.1} / {:6.1} ns",
+                c.entries,
+                c.lookup_bits,
+                c.build_times[0].as_secs_f64() * 1e6,
+                c.build_times[1].as_secs_f64() * 1e6,
+                c.decode_times[0].as_nanos() as f64 / c.codewords as f64,
+                c.decode_times[1].as_nanos() as f64 / c.codewords as f64,
+            );
+        }
+        let total = |f: fn(&Comparison) -> Duration| -> f64 {
+            comparisons.iter().map(f).sum::<Duration>().as_secs_f64() * 1e3
+        };
+        println!(
+            "Total build {:.2} / {:.2} ms, decode {:.1} / {:.1} ms",
+            total(|c| c.build_times[0]),
+            total(|c| c.build_times[1]),
+            total(|c| c.decode_times[0]),
+            total(|c| c.decode_times[1]),
+        );
     }
 }
@@ -0,0 +1,32 @@
+#![allow(clippy::bool_assert_comparison)]
+#![allow(clippy::bool_comparison)]
+#![allow(clippy::manual_div_ceil)] // Triggered by code generated by deku's derive macros
+#![allow(clippy::needless_bool)]
+#![forbid(unsafe_code)]
+
+mod audio;
+mod bit_reader;
+mod codebook;
+mod decoder;
+mod floor;
+mod huffman;
+mod mapping;
+mod mdct;
+mod mode;
+mod ogg;
+mod push;
+mod residue;
+#[cfg(test)]
+mod test_stream;
+mod time_domain;
+mod util;
+mod vorbis;
+mod window;
+
+pub use crate::{
+    audio::AudioError,
+    decoder::{stream_info, DecoderEvent, StreamInfo, VorbisDecoder},
+    ogg::{LogicalStream, OggError, OggPage, OggPageScanner, PageErrorPolicy},
+    push::PushDecoder,
+    vorbis::{CommentHeader, IdHeader, SetupError, SetupHeader, UserComment, VorbisError},
+};
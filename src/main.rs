@@ -1,72 +1,90 @@
-#![allow(clippy::bool_comparison)]
-#![allow(clippy::needless_bool)]
-#![allow(dead_code)]
 #![forbid(unsafe_code)]
 
-mod codebook;
-mod floor;
-mod huffman;
-mod mapping;
-mod mode;
-mod ogg;
-mod residue;
-mod time_domain;
-mod util;
-mod vorbis;
-
-//use bitstream_io::{BitRead, BitReader, LittleEndian};
-use crate::ogg::OggPage;
-use crate::vorbis::*;
-use deku::prelude::*;
-//use std::io::{Cursor, Read};
+use std::{env, fs::File, io::BufReader, process};
+use vorbis_decoder::{DecoderEvent, VorbisDecoder};
 
 fn main() {
-    let bytes = include_bytes!("../bin/frampton.ogg");
-    //let mut cursor = Cursor::new(&bytes);
-    //let mut reader = BitReader::endian(&mut cursor, LittleEndian);
-
-    /*
-    let ogg_page = OggPage::from_bytes((bytes, 0)).unwrap().1;
-    //dbg!(&ogg_page);
+    let path = env::args()
+        .nth(1)
+        .unwrap_or_else(|| "bin/plop.ogg".to_owned());
+    let file = File::open(&path).unwrap_or_else(|e| {
+        eprintln!("Unable to open {}: {}", path, e);
+        process::exit(1);
+    });
 
-    // Check decode
-    let to_bytes = ogg_page.to_bytes().unwrap();
-    assert_eq!(bytes[0..to_bytes.len()].to_vec(), to_bytes);
+    let mut decoder = VorbisDecoder::new(BufReader::new(file)).unwrap_or_else(|e| {
+        eprintln!("Unable to decode {}: {}", path, e);
+        process::exit(1);
+    });
 
-    // Check CRC
-    assert!(ogg_page.verify_crc());
-    */
+    let id_header = decoder.id_header();
+    println!("Channels:    {}", id_header.audio_channels());
+    println!("Sample rate: {} Hz", id_header.audio_sample_rate());
+    println!(
+        "Bitrate:     {} / {} / {} (min / nominal / max)",
+        id_header.bitrate_minimum(),
+        id_header.bitrate_nominal(),
+        id_header.bitrate_maximum()
+    );
+    println!(
+        "Blocksizes:  {} / {}",
+        id_header.blocksize_0(),
+        id_header.blocksize_1()
+    );
 
-    // Identification header
-    let (input, ogg_page) = OggPage::from_bytes((bytes, 0)).unwrap();
-    dbg!(&ogg_page);
-    let (ogg_data_remaining, id_header) = VorbisPacket::from_bytes((&ogg_page.data, 0)).unwrap();
-    assert!(ogg_data_remaining.0.is_empty()); // Unclear if this is a spec requirement
-    assert_eq!(ogg_data_remaining.1, 0);
-    //dbg!(&id_header);
-    match id_header.packet {
-        VorbisPacketType::Identification(id) => {
-            dbg!(&id);
-            dbg!(id.is_valid());
-        }
-        _ => panic!("Expected ID header type, got {:?}", id_header.packet),
+    let comment_header = decoder.comment_header();
+    println!("Vendor:      {}", comment_header.vendor_string());
+    for comment in comment_header.user_comments() {
+        println!("Comment:     {}", comment.comment());
     }
 
-    // Comment header
-    let (_input, ogg_page) = OggPage::from_bytes(input).unwrap();
-    dbg!(&ogg_page);
-    let (ogg_data_remaining, comment_header) =
-        VorbisPacket::from_bytes((&ogg_page.data, 0)).unwrap();
-    //dbg!(&comment_header);
-    match comment_header.packet {
-        VorbisPacketType::Comment(comment) => {
-            dbg!(&comment);
-            dbg!(comment.is_valid());
+    // Decode all the audio, reporting how much there was in each stream
+    let mut sample_rate = id_header.audio_sample_rate();
+    let mut packets = 0;
+    let mut samples = 0;
+    let mut peak: f32 = 0.0;
+    let mut gaps = 0;
+    loop {
+        let event = decoder.next_event().unwrap_or_else(|e| {
+            eprintln!("Unable to decode {}: {}", path, e);
+            process::exit(1);
+        });
+        match event {
+            Some(DecoderEvent::Pcm(pcm)) => {
+                packets += 1;
+                samples += pcm.first().map_or(0, |channel| channel.len());
+                for sample in pcm.iter().flatten() {
+                    peak = peak.max(sample.abs());
+                }
+            }
+            Some(DecoderEvent::StreamChanged {
+                channels,
+                sample_rate: new_sample_rate,
+                ..
+            }) => {
+                print_totals(packets, samples, sample_rate, peak);
+                println!("New stream:  {} channels, {} Hz", channels, new_sample_rate);
+                sample_rate = new_sample_rate;
+                packets = 0;
+                samples = 0;
+                peak = 0.0;
+            }
+            Some(DecoderEvent::PacketsLost) => gaps += 1,
+            None => break,
         }
-        x => panic!("Expected comment header type, got {:?}", x),
     }
+    if gaps > 0 {
+        println!("Gaps:        {} (packets lost to damaged pages)", gaps);
+    }
+    print_totals(packets, samples, sample_rate, peak);
+}
 
-    // Setup header
-    dbg!(&ogg_data_remaining); // Dev hack. Second Ogg page has both comment and setup headers
-    let _setup_header = SetupHeader::from_bytes(ogg_data_remaining).unwrap();
+fn print_totals(packets: usize, samples: usize, sample_rate: u32, peak: f32) {
+    println!("Packets:     {}", packets);
+    println!(
+        "Samples:     {} per channel ({:.3} s)",
+        samples,
+        samples as f64 / sample_rate as f64
+    );
+    println!("Peak:        {:.6}", peak);
 }
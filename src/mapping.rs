@@ -4,9 +4,6 @@ use thiserror::Error;
 
 #[derive(Debug)]
 pub struct Mapping {
-    mapping_type: u16,
-    submaps: u8,
-    coupling_steps: u8,
     magnitude: Vec<u8>,
     angle: Vec<u8>,
     mux: Vec<u8>,
@@ -14,14 +11,16 @@ pub struct Mapping {
 }
 
 impl Mapping {
-    pub fn decode<R, E>(reader: &mut BitReader<R, E>) -> Result<Self, MappingError>
+    /// Decode a mapping for a stream with `audio_channels` channels, which
+    /// sets the size of the coupling channel numbers and of the mux.
+    pub fn decode<R, E>(
+        reader: &mut BitReader<R, E>,
+        audio_channels: u8,
+    ) -> Result<Self, MappingError>
     where
         R: std::io::Read,
         E: bitstream_io::Endianness,
     {
-        // TODO: use audio_channels from the ID header instead
-        let audio_channels: u8 = 1;
-
         let mapping_type = reader.read(16)?;
         if mapping_type != 0 {
             return Err(MappingError::InvalidMappingType(mapping_type));
@@ -37,7 +36,7 @@ impl Mapping {
         let flag: bool = reader.read_bit()?;
         let mut magnitude: Vec<u8> = Vec::new();
         let mut angle: Vec<u8> = Vec::new();
-        let coupling_steps = if flag == true {
+        if flag == true {
             // Polar channel mapping is in use
             let coupling_steps = reader.read::<u8>(8)? + 1;
             for _ in 0..coupling_steps {
@@ -63,10 +62,7 @@ impl Mapping {
                 magnitude.push(m);
                 angle.push(a);
             }
-            coupling_steps
-        } else {
-            0
-        };
+        }
 
         let reserved: u8 = reader.read(2)?;
         if reserved != 0 {
@@ -96,15 +92,66 @@ impl Mapping {
             .collect::<Result<_, _>>()?;
 
         Ok(Self {
-            mapping_type,
-            submaps,
-            coupling_steps,
             magnitude,
             angle,
             mux,
             submaps_vec,
         })
     }
+
+    /// Check that each submap's floor and residue numbers are among the
+    /// `floor_count` floors and `residue_count` residues configured.
+    pub fn validate(&self, floor_count: usize, residue_count: usize) -> Result<(), MappingError> {
+        for submap in &self.submaps_vec {
+            if submap.floor as usize >= floor_count {
+                return Err(MappingError::FloorOutOfRange(submap.floor));
+            }
+            if submap.residue as usize >= residue_count {
+                return Err(MappingError::ResidueOutOfRange(submap.residue));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn mux(&self) -> &[u8] {
+        &self.mux
+    }
+
+    pub fn submaps(&self) -> &[Submap] {
+        &self.submaps_vec
+    }
+
+    /// Magnitude and angle channel numbers of each coupling step, in order.
+    pub fn coupling_pairs(&self) -> impl DoubleEndedIterator<Item = (usize, usize)> + '_ {
+        self.magnitude
+            .iter()
+            .zip(self.angle.iter())
+            .map(|(m, a)| (*m as usize, *a as usize))
+    }
+
+    /// Undo square polar channel coupling on the residue vectors, restoring
+    /// independent channels. Coupling steps are undone in reverse order.
+    pub fn inverse_couple(&self, vectors: &mut [Vec<f32>]) {
+        for (magnitude, angle) in self.coupling_pairs().rev() {
+            for i in 0..vectors[magnitude].len() {
+                let m = vectors[magnitude][i];
+                let a = vectors[angle][i];
+                let (new_m, new_a) = if m > 0.0 {
+                    if a > 0.0 {
+                        (m, m - a)
+                    } else {
+                        (m + a, m)
+                    }
+                } else if a > 0.0 {
+                    (m, m + a)
+                } else {
+                    (m - a, m)
+                };
+                vectors[magnitude][i] = new_m;
+                vectors[angle][i] = new_a;
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -122,13 +169,18 @@ impl Submap {
         let _: u8 = reader.read(8)?; // Unused time configuration placeholder
 
         let floor = reader.read(8)?;
-        // TODO: verify the floor number is not greater than the highest number floor configured for the bitstream
-
         let residue = reader.read(8)?;
-        // TODO: verify the residue number is not greater than the highest number residue configured for the bitstream
 
         Ok(Self { floor, residue })
     }
+
+    pub fn floor(&self) -> u8 {
+        self.floor
+    }
+
+    pub fn residue(&self) -> u8 {
+        self.residue
+    }
 }
 
 #[derive(Debug, Error)]
@@ -151,6 +203,12 @@ pub enum MappingError {
     #[error("Mux {0} is greater than highest submap")]
     MuxInvalid(u8),
 
+    #[error("Submap floor {0} is not configured")]
+    FloorOutOfRange(u8),
+
+    #[error("Submap residue {0} is not configured")]
+    ResidueOutOfRange(u8),
+
     // Represents all cases of `std::io::Error`.
     #[error(transparent)]
     IOError(#[from] std::io::Error),
@@ -159,7 +217,175 @@ pub enum MappingError {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::test_stream::Rng;
+    use bitstream_io::{BitWrite, BitWriter, LittleEndian};
+    use std::io::Cursor;
+
+    fn test_mapping(channels: u8, coupling_pairs: &[(u8, u8)]) -> Mapping {
+        Mapping {
+            magnitude: coupling_pairs.iter().map(|(m, _)| *m).collect(),
+            angle: coupling_pairs.iter().map(|(_, a)| *a).collect(),
+            mux: vec![0; channels as usize],
+            submaps_vec: vec![Submap {
+                floor: 0,
+                residue: 0,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_mapping_decode() {
+        let mapping_packet = |coupling_pairs: &[(u32, u32)], mux: &[u32]| {
+            let mut writer = BitWriter::endian(Vec::new(), LittleEndian);
+            writer.write(16, 0).unwrap(); // Mapping type
+            writer.write_bit(true).unwrap();
+            writer.write(4, 1).unwrap(); // Submaps - 1
+            writer.write_bit(true).unwrap();
+            writer.write(8, coupling_pairs.len() as u32 - 1).unwrap();
+            for (magnitude, angle) in coupling_pairs {
+                writer.write(3, *magnitude).unwrap();
+                writer.write(3, *angle).unwrap();
+            }
+            writer.write(2, 0).unwrap(); // Reserved
+            for submap in mux {
+                writer.write(4, *submap).unwrap();
+            }
+            for (floor, residue) in [(0, 1), (1, 0)] {
+                writer.write(8, 0).unwrap(); // Time configuration
+                writer.write(8, floor).unwrap();
+                writer.write(8, residue).unwrap();
+            }
+            writer.byte_align().unwrap();
+            writer.into_writer()
+        };
+        let decode = |packet: &[u8]| {
+            let mut cursor = Cursor::new(packet);
+            let mut reader = BitReader::endian(&mut cursor, LittleEndian);
+            Mapping::decode(&mut reader, 6)
+        };
+
+        // 5.1 channels take 3 bits for each coupling channel number, and
+        // each channel has a mux entry
+        let packet = mapping_packet(&[(0, 2), (1, 2), (4, 5)], &[0, 0, 0, 1, 1, 1]);
+        let mapping = decode(&packet).unwrap();
+        assert_eq!(
+            mapping.coupling_pairs().collect::<Vec<_>>(),
+            vec![(0, 2), (1, 2), (4, 5)]
+        );
+        assert_eq!(mapping.mux(), &[0, 0, 0, 1, 1, 1]);
+        assert_eq!(mapping.submaps().len(), 2);
+        assert_eq!(mapping.submaps()[1].floor(), 1);
+        assert_eq!(mapping.submaps()[1].residue(), 0);
+
+        let packet = mapping_packet(&[(6, 2)], &[0; 6]);
+        assert!(matches!(
+            decode(&packet),
+            Err(MappingError::PolarMagInvalid(6))
+        ));
+        let packet = mapping_packet(&[(0, 7)], &[0; 6]);
+        assert!(matches!(
+            decode(&packet),
+            Err(MappingError::PolarAngInvalid(7))
+        ));
+        let packet = mapping_packet(&[(3, 3)], &[0; 6]);
+        assert!(matches!(
+            decode(&packet),
+            Err(MappingError::PolarAngEqualsMag(3, 3))
+        ));
+        let packet = mapping_packet(&[(0, 1)], &[0, 0, 2, 0, 0, 0]);
+        assert!(matches!(decode(&packet), Err(MappingError::MuxInvalid(2))));
+    }
+
+    /// Square polar coupling as done by an encoder: the magnitude is the
+    /// larger of the two values and the angle is their difference, with the
+    /// sign chosen so that `inverse_couple` can tell the cases apart.
+    fn forward_couple(mapping: &Mapping, vectors: &mut [Vec<f32>]) {
+        for (magnitude, angle) in mapping.coupling_pairs() {
+            for i in 0..vectors[magnitude].len() {
+                let l = vectors[magnitude][i];
+                let r = vectors[angle][i];
+                let (m, a) = if l.abs() > r.abs() {
+                    (l, if l > 0.0 { l - r } else { r - l })
+                } else {
+                    (r, if r > 0.0 { l - r } else { r - l })
+                };
+                vectors[magnitude][i] = m;
+                vectors[angle][i] = a;
+            }
+        }
+    }
+
+    /// Random values in quarter steps, so that coupling is exact
+    fn random_vectors(rng: &mut Rng, channels: usize, n: usize) -> Vec<Vec<f32>> {
+        (0..channels)
+            .map(|_| {
+                (0..n)
+                    .map(|_| (rng.below(65) as i32 - 32) as f32 / 4.0)
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_mapping_validate() {
+        let mapping = test_mapping(2, &[(0, 1)]);
+        assert!(mapping.validate(1, 1).is_ok());
+        assert!(matches!(
+            mapping.validate(0, 1),
+            Err(MappingError::FloorOutOfRange(0))
+        ));
+        assert!(matches!(
+            mapping.validate(1, 0),
+            Err(MappingError::ResidueOutOfRange(0))
+        ));
+    }
 
     #[test]
-    fn test_() {}
+    fn test_inverse_couple() {
+        // One value in each quadrant, plus ties and zeros
+        let mapping = test_mapping(2, &[(0, 1)]);
+        let mut vectors = vec![
+            vec![3.0, 3.0, -3.0, -3.0, 2.0, 0.0],
+            vec![1.0, -1.0, 1.0, -1.0, 2.0, 0.0],
+        ];
+        mapping.inverse_couple(&mut vectors);
+        assert_eq!(
+            vectors,
+            vec![
+                vec![3.0, 2.0, -3.0, -2.0, 2.0, 0.0],
+                vec![2.0, 3.0, -2.0, -3.0, 0.0, 0.0],
+            ]
+        );
+
+        // Without coupling steps the vectors are left alone
+        let mapping = test_mapping(2, &[]);
+        let mut rng = Rng::new(1);
+        let input = random_vectors(&mut rng, 2, 16);
+        let mut vectors = input.clone();
+        mapping.inverse_couple(&mut vectors);
+        assert_eq!(vectors, input);
+    }
+
+    #[test]
+    fn test_inverse_couple_round_trip() {
+        // Stereo, and chains of steps sharing channels, which only round trip
+        // if the steps are undone in reverse order
+        let configs: [(u8, &[(u8, u8)]); 4] = [
+            (2, &[(0, 1)]),
+            (2, &[(1, 0)]),
+            (3, &[(0, 1), (1, 2), (2, 0)]),
+            (6, &[(0, 2), (1, 2), (3, 4), (2, 5), (0, 1)]),
+        ];
+        let mut rng = Rng::new(7);
+        for (channels, coupling_pairs) in configs {
+            let mapping = test_mapping(channels, coupling_pairs);
+            for _ in 0..50 {
+                let input = random_vectors(&mut rng, channels as usize, 32);
+                let mut vectors = input.clone();
+                forward_couple(&mapping, &mut vectors);
+                mapping.inverse_couple(&mut vectors);
+                assert_eq!(vectors, input);
+            }
+        }
+    }
 }
@@ -0,0 +1,236 @@
+use std::f64::consts::PI;
+use std::ops::{Add, Mul, Sub};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Complex {
+    re: f32,
+    im: f32,
+}
+
+impl Complex {
+    fn new(re: f32, im: f32) -> Self {
+        Self { re, im }
+    }
+
+    /// `e^(-i * angle)`, computed in double precision
+    fn unit(angle: f64) -> Self {
+        Self::new(angle.cos() as f32, -angle.sin() as f32)
+    }
+}
+
+impl Add for Complex {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self::new(self.re + other.re, self.im + other.im)
+    }
+}
+
+impl Sub for Complex {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Self::new(self.re - other.re, self.im - other.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Self;
+
+    fn mul(self, other: Self) -> Self {
+        Self::new(
+            self.re * other.re - self.im * other.im,
+            self.re * other.im + self.im * other.re,
+        )
+    }
+}
+
+/// Inverse MDCT for one blocksize, with its twiddle factors precomputed.
+///
+/// The N/2 spectral coefficients go through a DCT-IV, done as an N/4 point
+/// complex FFT between two twiddle passes, and the N time domain samples are
+/// then unfolded from its output using the transform's symmetries.
+#[derive(Debug)]
+pub struct Mdct {
+    n: usize,
+    /// `e^(-2πi(k + 1/8) / N)`, applied before and after the FFT
+    twiddles: Vec<Complex>,
+    /// `e^(-2πik / (N/4))` for the first half of the FFT's roots of unity
+    roots: Vec<Complex>,
+    /// Bit reversed index of each FFT input
+    bit_reverse: Vec<usize>,
+}
+
+impl Mdct {
+    /// Set up the transform for blocks of `n` samples. `n` must be a power of
+    /// two, at least 16.
+    pub fn new(n: usize) -> Self {
+        assert!(n.is_power_of_two() && n >= 16);
+        let n4 = n / 4;
+        let twiddles = (0..n4)
+            .map(|k| Complex::unit(2.0 * PI * (k as f64 + 0.125) / n as f64))
+            .collect();
+        let roots = (0..n4 / 2)
+            .map(|k| Complex::unit(2.0 * PI * k as f64 / n4 as f64))
+            .collect();
+        let bits = n4.trailing_zeros();
+        let bit_reverse = (0..n4)
+            .map(|i| i.reverse_bits() >> (usize::BITS - bits))
+            .collect();
+
+        Self {
+            n,
+            twiddles,
+            roots,
+            bit_reverse,
+        }
+    }
+
+    /// Turn the N/2 spectral coefficients in `input` into N time domain
+    /// samples.
+    pub fn inverse(&self, input: &[f32]) -> Vec<f32> {
+        let n2 = self.n / 2;
+        let n4 = self.n / 4;
+        assert_eq!(input.len(), n2);
+
+        // Pair up even coefficients from the front with odd ones from the
+        // back, twiddle them and put them in bit reversed order for the FFT
+        let mut buffer = vec![Complex::default(); n4];
+        for (k, twiddle) in self.twiddles.iter().enumerate() {
+            let x = Complex::new(input[2 * k], input[n2 - 1 - 2 * k]);
+            buffer[self.bit_reverse[k]] = x * *twiddle;
+        }
+        self.fft(&mut buffer);
+
+        // The DCT-IV of the input, with even outputs in the real parts and
+        // odd outputs, from the back, in the negated imaginary parts
+        let mut dct = vec![0.0; n2];
+        for (k, (x, twiddle)) in buffer.iter().zip(&self.twiddles).enumerate() {
+            let y = *x * *twiddle;
+            dct[2 * k] = y.re;
+            dct[n2 - 1 - 2 * k] = -y.im;
+        }
+
+        // The first quarter of the block is the second half of the DCT-IV,
+        // the middle half is the whole of it reversed and negated, and the
+        // last quarter is the first half negated
+        let mut output = Vec::with_capacity(self.n);
+        output.extend_from_slice(&dct[n4..]);
+        output.extend(dct.iter().rev().map(|x| -x));
+        output.extend(dct[..n4].iter().map(|x| -x));
+        output
+    }
+
+    /// In place radix-2 FFT of `buffer`, which must already be in bit
+    /// reversed order.
+    fn fft(&self, buffer: &mut [Complex]) {
+        let len = buffer.len();
+        let mut size = 2;
+        while size <= len {
+            let half = size / 2;
+            let stride = len / size;
+            for start in (0..len).step_by(size) {
+                for j in 0..half {
+                    let t = buffer[start + j + half] * self.roots[j * stride];
+                    let u = buffer[start + j];
+                    buffer[start + j] = u + t;
+                    buffer[start + j + half] = u - t;
+                }
+            }
+            size *= 2;
+        }
+    }
+}
+
+/// Inverse MDCT evaluated directly from the transform's definition, as a
+/// reference for `Mdct`.
+#[cfg(test)]
+pub fn inverse_mdct_naive(input: &[f32]) -> Vec<f32> {
+    let n2 = input.len();
+    let n = n2 * 2;
+    (0..n)
+        .map(|i| {
+            let mut sum = 0.0;
+            for (k, x) in input.iter().enumerate() {
+                let angle =
+                    2.0 * PI / n as f64 * (i as f64 + 0.5 + n as f64 / 4.0) * (k as f64 + 0.5);
+                sum += *x as f64 * angle.cos();
+            }
+            sum as f32
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_stream::Rng;
+
+    #[test]
+    fn test_inverse_mdct_naive() {
+        // A single coefficient produces a cosine
+        let mut input = vec![0.0; 32];
+        input[3] = 1.0;
+        let output = inverse_mdct_naive(&input);
+        assert_eq!(output.len(), 64);
+        for (i, y) in output.iter().enumerate() {
+            let expected = (2.0 * PI / 64.0 * (i as f64 + 0.5 + 16.0) * 3.5).cos();
+            assert!((*y as f64 - expected).abs() < 1e-6);
+        }
+
+        // Time domain aliasing: the first half is odd symmetric and the second half is even symmetric
+        let input: Vec<f32> = (0..32).map(|i| (i as f32 * 0.37).sin()).collect();
+        let output = inverse_mdct_naive(&input);
+        for i in 0..16 {
+            assert!((output[i] + output[31 - i]).abs() < 1e-4);
+            assert!((output[32 + i] - output[63 - i]).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_inverse_mdct() {
+        fn assert_close(output: &[f32], expected: &[f32]) {
+            assert_eq!(output.len(), expected.len());
+            let scale = expected.iter().fold(1.0_f32, |max, y| max.max(y.abs()));
+            for (y, expected) in output.iter().zip(expected) {
+                assert!(
+                    (y - expected).abs() <= 1e-5 * scale,
+                    "{} != {}",
+                    y,
+                    expected
+                );
+            }
+        }
+
+        // Every legal blocksize, with single coefficients checked against
+        // the cosine they produce and random spectra against the naive
+        // transform, which is too slow to run on the largest blocks
+        let mut rng = Rng::new(3);
+        for bits in 6..=13 {
+            let n = 1 << bits;
+            let mdct = Mdct::new(n);
+            assert_eq!(mdct.n, n);
+
+            for k in [0, 1, n / 4, n / 2 - 1] {
+                let mut input = vec![0.0; n / 2];
+                input[k] = 1.0;
+                let expected: Vec<f32> = (0..n)
+                    .map(|i| {
+                        let angle = 2.0 * PI / n as f64
+                            * (i as f64 + 0.5 + n as f64 / 4.0)
+                            * (k as f64 + 0.5);
+                        angle.cos() as f32
+                    })
+                    .collect();
+                assert_close(&mdct.inverse(&input), &expected);
+            }
+
+            if n <= 2048 {
+                let input: Vec<f32> = (0..n / 2)
+                    .map(|_| (rng.below(2001) as f32 - 1000.0) / 100.0)
+                    .collect();
+                assert_close(&mdct.inverse(&input), &inverse_mdct_naive(&input));
+            }
+        }
+    }
+}
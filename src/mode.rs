@@ -4,8 +4,6 @@ use thiserror::Error;
 #[derive(Debug)]
 pub struct Mode {
     blockflag: bool,
-    window_type: u16,
-    transform_type: u16,
     mapping: u8,
 }
 
@@ -27,14 +25,25 @@ impl Mode {
             return Err(ModeError::InvalidTransformType(transform_type));
         }
         let mapping = reader.read(8)?;
-        // TODO: verify mapping is not greater than the highest number mapping in use
-
-        Ok(Self {
-            blockflag,
-            window_type,
-            transform_type,
-            mapping,
-        })
+
+        Ok(Self { blockflag, mapping })
+    }
+
+    /// Check that the mapping number is among the `mapping_count` mappings
+    /// configured.
+    pub fn validate(&self, mapping_count: usize) -> Result<(), ModeError> {
+        if self.mapping as usize >= mapping_count {
+            return Err(ModeError::MappingOutOfRange(self.mapping));
+        }
+        Ok(())
+    }
+
+    pub fn blockflag(&self) -> bool {
+        self.blockflag
+    }
+
+    pub fn mapping(&self) -> u8 {
+        self.mapping
     }
 }
 
@@ -46,6 +55,9 @@ pub enum ModeError {
     #[error("Invalid transform type: {0}")]
     InvalidTransformType(u16),
 
+    #[error("Mapping {0} is not configured")]
+    MappingOutOfRange(u8),
+
     // Represents all cases of `std::io::Error`.
     #[error(transparent)]
     IOError(#[from] std::io::Error),
@@ -55,6 +67,19 @@ pub enum ModeError {
 mod test {
     use super::*;
 
+    #[test]
+    fn test_mode_validate() {
+        let mode = Mode {
+            blockflag: false,
+            mapping: 1,
+        };
+        assert!(mode.validate(2).is_ok());
+        assert!(matches!(
+            mode.validate(1),
+            Err(ModeError::MappingOutOfRange(1))
+        ));
+    }
+
     #[test]
     fn test_() {}
 }
@@ -1,10 +1,21 @@
 use crc_any::CRCu32;
 use deku::prelude::*;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{Read, Seek, SeekFrom},
+};
+use thiserror::Error;
+
+/// Length of the fixed part of a page header, up to and including `page_segments`.
+const PAGE_HEADER_LEN: usize = 27;
+
+const CAPTURE_PATTERN: &[u8; 4] = b"OggS";
 
 #[derive(Debug, DekuRead, DekuWrite)]
 struct HeaderTypeFlag(u8);
 
 impl HeaderTypeFlag {
+    #[cfg(test)]
     fn is_valid(&self) -> bool {
         (self.0 & !0x07) == 0
     }
@@ -40,6 +51,43 @@ pub struct OggPage {
 }
 
 impl OggPage {
+    /// Read the next page from `reader`. Returns `None` if the stream ends
+    /// cleanly on a page boundary.
+    pub fn read<R: Read>(reader: &mut R) -> Result<Option<Self>, OggError> {
+        let mut bytes = vec![0; PAGE_HEADER_LEN];
+        let header_len = read_fully(reader, &mut bytes)?;
+        if header_len == 0 {
+            return Ok(None);
+        } else if header_len < PAGE_HEADER_LEN {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+
+        let page_segments = bytes[PAGE_HEADER_LEN - 1] as usize;
+        bytes.resize(PAGE_HEADER_LEN + page_segments, 0);
+        reader.read_exact(&mut bytes[PAGE_HEADER_LEN..])?;
+
+        let data_len: usize = bytes[PAGE_HEADER_LEN..].iter().map(|b| *b as usize).sum();
+        let data_start = bytes.len();
+        bytes.resize(data_start + data_len, 0);
+        reader.read_exact(&mut bytes[data_start..])?;
+
+        let (_, page) = Self::from_bytes((&bytes, 0))?;
+        Ok(Some(page))
+    }
+
+    /// Length of the page in bytes, header included.
+    pub fn byte_len(&self) -> usize {
+        PAGE_HEADER_LEN + self.segment_table.len() + self.data.len()
+    }
+
+    pub fn absolute_granule_position(&self) -> u64 {
+        self.absolute_granule_position
+    }
+
+    pub fn stream_serial_number(&self) -> u32 {
+        self.stream_serial_number
+    }
+
     pub fn verify_crc(&self) -> bool {
         let mut bytes = self.to_bytes().expect("OggPage DekuWrite failed!");
         bytes[22] = 0;
@@ -54,6 +102,722 @@ impl OggPage {
     }
 }
 
+/// Reads pages from a byte stream, and can search forward for the next good
+/// page when the stream is damaged or joined part way through a page.
+pub struct OggPageScanner<R: Read> {
+    reader: R,
+    /// Bytes read from `reader` that haven't been used yet
+    buffer: Vec<u8>,
+    /// Total number of bytes skipped while searching for pages
+    bytes_discarded: u64,
+}
+
+impl<R: Read> OggPageScanner<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buffer: Vec::new(),
+            bytes_discarded: 0,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// Total number of bytes skipped by `scan_page` so far.
+    pub fn bytes_discarded(&self) -> u64 {
+        self.bytes_discarded
+    }
+
+    /// Read the page that starts at the current position. Returns `None` if
+    /// the stream ends cleanly on a page boundary.
+    pub fn read_page(&mut self) -> Result<Option<OggPage>, OggError> {
+        match self.peek_page()? {
+            Some((page, len)) => {
+                self.buffer.drain(..len);
+                Ok(Some(page))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Search forward for the next page with a valid checksum, skipping any
+    /// bytes before it. Returns `None` if the stream ends first.
+    pub fn scan_page(&mut self) -> Result<Option<OggPage>, OggError> {
+        loop {
+            match self.buffer.windows(4).position(|w| w == CAPTURE_PATTERN) {
+                Some(i) => self.discard(i),
+                None => {
+                    // Keep what could be the start of a capture pattern
+                    self.discard(self.buffer.len().saturating_sub(3));
+                    let len = self.buffer.len();
+                    self.fill(len + 4096)?;
+                    if self.buffer.len() == len {
+                        self.discard(len);
+                        return Ok(None);
+                    }
+                    continue;
+                }
+            }
+
+            match self.peek_page() {
+                Ok(Some((page, len))) if page.verify_crc() == true => {
+                    self.buffer.drain(..len);
+                    return Ok(Some(page));
+                }
+                Ok(_) => (),
+                Err(OggError::IOError(e)) if e.kind() != std::io::ErrorKind::UnexpectedEof => {
+                    return Err(e.into())
+                }
+                Err(_) => (), // Not a page after all
+            }
+            self.discard(1);
+        }
+    }
+
+    /// Parse the page at the start of the buffer without using it up.
+    /// Returns the page along with its length in bytes.
+    fn peek_page(&mut self) -> Result<Option<(OggPage, usize)>, OggError> {
+        if self.fill(PAGE_HEADER_LEN)? == false {
+            if self.buffer.is_empty() == true {
+                return Ok(None);
+            }
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        if self.buffer.starts_with(CAPTURE_PATTERN) == false {
+            return Err(OggError::MissingCapturePattern);
+        }
+
+        let page_segments = self.buffer[PAGE_HEADER_LEN - 1] as usize;
+        let header_len = PAGE_HEADER_LEN + page_segments;
+        if self.fill(header_len)? == false {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        let data_len: usize = self.buffer[PAGE_HEADER_LEN..header_len]
+            .iter()
+            .map(|b| *b as usize)
+            .sum();
+        if self.fill(header_len + data_len)? == false {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+
+        let (_, page) = OggPage::from_bytes((&self.buffer[..header_len + data_len], 0))?;
+        Ok(Some((page, header_len + data_len)))
+    }
+
+    /// Read until at least `len` bytes are buffered. Returns false if the
+    /// stream ends first. Whatever was read before an error is kept, so
+    /// reading can carry on once a reader that would block has more to give.
+    fn fill(&mut self, len: usize) -> std::io::Result<bool> {
+        while self.buffer.len() < len {
+            let buffered = self.buffer.len();
+            self.buffer.resize(len, 0);
+            match self.reader.read(&mut self.buffer[buffered..]) {
+                Ok(0) => {
+                    self.buffer.truncate(buffered);
+                    return Ok(false);
+                }
+                Ok(read) => self.buffer.truncate(buffered + read),
+                Err(e) => {
+                    self.buffer.truncate(buffered);
+                    if e.kind() != std::io::ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    fn discard(&mut self, len: usize) {
+        self.buffer.drain(..len);
+        self.bytes_discarded += len as u64;
+    }
+}
+
+/// What to do with a page whose checksum is wrong, or whose sequence number
+/// shows that pages are missing before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PageErrorPolicy {
+    /// Fail with an error
+    Strict,
+    /// Drop the damaged page and whatever packets were in progress, and
+    /// carry on with the next page
+    #[default]
+    Skip,
+    /// Use the page as it is
+    Ignore,
+}
+
+/// A logical stream multiplexed with the one an `OggPacketReader` reads.
+#[derive(Debug)]
+pub struct LogicalStream {
+    serial: u32,
+    /// First packet of the stream, which identifies its codec
+    first_packet: Option<Vec<u8>>,
+    partial_packet: Vec<u8>,
+    skip_continued_packet: bool,
+    /// Complete packets not taken yet
+    packets: VecDeque<Vec<u8>>,
+    is_ended: bool,
+}
+
+impl LogicalStream {
+    fn new(serial: u32) -> Self {
+        Self {
+            serial,
+            first_packet: None,
+            partial_packet: Vec::new(),
+            skip_continued_packet: false,
+            packets: VecDeque::new(),
+            is_ended: false,
+        }
+    }
+
+    pub fn serial(&self) -> u32 {
+        self.serial
+    }
+
+    /// The packet that began the stream, if its first page was seen.
+    pub fn first_packet(&self) -> Option<&[u8]> {
+        self.first_packet.as_deref()
+    }
+
+    /// Whether the last page of the stream was seen.
+    pub fn is_ended(&self) -> bool {
+        self.is_ended
+    }
+
+    /// Take the next complete packet of the stream.
+    pub fn next_packet(&mut self) -> Option<Vec<u8>> {
+        self.packets.pop_front()
+    }
+
+    /// Add the packets on `page`, keeping them if `keep_packets` is set.
+    /// Packets with pieces missing are dropped.
+    fn push_page(&mut self, page: &OggPage, keep_packets: bool) {
+        let is_first_page = page.header_type_flag.is_first_page();
+        if page.header_type_flag.is_continued_packet() == false {
+            self.partial_packet.clear();
+            self.skip_continued_packet = false;
+        } else if self.partial_packet.is_empty() == true {
+            self.skip_continued_packet = true;
+        }
+
+        let mut offset = 0;
+        for &lacing_value in &page.segment_table {
+            let lacing_value = lacing_value as usize;
+            let segment_data = &page.data[offset..offset + lacing_value];
+            offset += lacing_value;
+            if self.skip_continued_packet == true {
+                self.skip_continued_packet = lacing_value == 255;
+                continue;
+            }
+            self.partial_packet.extend_from_slice(segment_data);
+            if lacing_value < 255 {
+                let packet = std::mem::take(&mut self.partial_packet);
+                if is_first_page == true && self.first_packet.is_none() {
+                    self.first_packet = Some(packet.clone());
+                }
+                if keep_packets == true {
+                    self.packets.push_back(packet);
+                }
+            }
+        }
+        if page.header_type_flag.is_last_page() == true {
+            self.is_ended = true;
+        }
+    }
+}
+
+/// Reassembles packets from a sequence of Ogg pages using the lacing values in
+/// each page's segment table. A lacing value of 255 means the packet continues
+/// in the next segment, which may be on the next page; any other value ends it.
+///
+/// Pages of every logical stream are read alike, unless `select_stream` picks
+/// out one of them. The others are then set aside by serial number.
+pub struct OggPacketReader<R: Read> {
+    scanner: OggPageScanner<R>,
+    page: Option<OggPage>,
+    segment: usize,
+    offset: usize,
+    partial_packet: Vec<u8>,
+    /// Byte offset of the next page, from where the reader started
+    position: u64,
+    /// Bytes skipped by the scanner that `position` already counts. Reading
+    /// a page can fail after skipping some, when the reader would block.
+    bytes_discarded: u64,
+    /// Where the reader started, once it's needed for seeking
+    start: Option<u64>,
+    /// Whether to drop the rest of a packet that began before a seek or a gap
+    skip_continued_packet: bool,
+    policy: PageErrorPolicy,
+    /// Serial and sequence number of the last page read
+    previous_page: Option<(u32, u32)>,
+    /// Whether packets were lost since the last packet returned
+    packets_lost: bool,
+    /// Whether packets were lost just before the last packet returned
+    follows_gap: bool,
+    /// Whether the packet being read is the first of a logical stream
+    starts_stream: bool,
+    /// Picks out the logical stream to read by its first packet
+    identify: Option<fn(&[u8]) -> bool>,
+    /// Serial number of the logical stream being read, once picked out
+    serial: Option<u32>,
+    /// Whether the last page of the logical stream being read was seen
+    is_ended: bool,
+    /// The other logical streams, by serial number
+    other_streams: BTreeMap<u32, LogicalStream>,
+    /// Whether to keep the packets of the other logical streams
+    keep_other_packets: bool,
+}
+
+impl<R: Read> OggPacketReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            scanner: OggPageScanner::new(reader),
+            page: None,
+            segment: 0,
+            offset: 0,
+            partial_packet: Vec::new(),
+            position: 0,
+            bytes_discarded: 0,
+            start: None,
+            skip_continued_packet: false,
+            policy: PageErrorPolicy::default(),
+            previous_page: None,
+            packets_lost: false,
+            follows_gap: false,
+            starts_stream: false,
+            identify: None,
+            serial: None,
+            is_ended: false,
+            other_streams: BTreeMap::new(),
+            keep_other_packets: false,
+        }
+    }
+
+    /// Only read packets from the logical stream whose first packet
+    /// `identify` accepts. In a chained file the next one is picked out once
+    /// that stream ends.
+    pub fn select_stream(&mut self, identify: fn(&[u8]) -> bool) {
+        self.identify = Some(identify);
+    }
+
+    /// The logical streams other than the one selected, in order of serial
+    /// number.
+    pub fn other_streams(&self) -> impl Iterator<Item = &LogicalStream> {
+        self.other_streams.values()
+    }
+
+    pub fn other_stream_mut(&mut self, serial: u32) -> Option<&mut LogicalStream> {
+        self.other_streams.get_mut(&serial)
+    }
+
+    /// Set whether to keep the packets of the other logical streams until
+    /// they're taken. By default they're dropped, apart from the first packet
+    /// of each stream, so memory doesn't grow when they aren't wanted.
+    pub fn set_keep_other_packets(&mut self, keep: bool) {
+        self.keep_other_packets = keep;
+        if keep == false {
+            for stream in self.other_streams.values_mut() {
+                stream.packets.clear();
+            }
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        self.scanner.get_mut()
+    }
+
+    /// Set how damaged and missing pages are handled.
+    pub fn set_page_error_policy(&mut self, policy: PageErrorPolicy) {
+        self.policy = policy;
+    }
+
+    /// Byte offset of the next page to be read, counted from where the
+    /// reader was when the packet reader was created.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Total number of bytes skipped to get back to a page after finding
+    /// something else where a page should have started.
+    pub fn bytes_discarded(&self) -> u64 {
+        self.scanner.bytes_discarded()
+    }
+
+    /// Read the next complete packet. Returns `None` at the end of the stream.
+    pub fn next_packet(&mut self) -> Result<Option<Vec<u8>>, OggError> {
+        loop {
+            if let Some(page) = &self.page {
+                while self.segment < page.segment_table.len() {
+                    let lacing_value = page.segment_table[self.segment] as usize;
+                    let segment_data = &page.data[self.offset..self.offset + lacing_value];
+                    self.segment += 1;
+                    self.offset += lacing_value;
+                    if self.skip_continued_packet == true {
+                        self.skip_continued_packet = lacing_value == 255;
+                        continue;
+                    }
+                    if self.partial_packet.is_empty() == true {
+                        self.starts_stream =
+                            page.header_type_flag.is_first_page() == true && self.segment == 1;
+                    }
+                    self.partial_packet.extend_from_slice(segment_data);
+                    if lacing_value < 255 {
+                        self.follows_gap = std::mem::take(&mut self.packets_lost);
+                        return Ok(Some(std::mem::take(&mut self.partial_packet)));
+                    }
+                }
+            }
+
+            // The current page is used up, so move to the next one
+            let page = match self.scanner.read_page() {
+                Ok(page) => page,
+                Err(OggError::MissingCapturePattern) if self.policy != PageErrorPolicy::Strict => {
+                    self.scanner.scan_page()?
+                }
+                Err(e) => return Err(e),
+            };
+            let bytes_discarded = self.scanner.bytes_discarded() - self.bytes_discarded;
+            self.bytes_discarded = self.scanner.bytes_discarded();
+            self.position += bytes_discarded;
+            let page = match page {
+                Some(page) => page,
+                None if self.partial_packet.is_empty() == true => return Ok(None),
+                None => return Err(OggError::TruncatedPacket),
+            };
+            self.position += page.byte_len() as u64;
+
+            if page.verify_crc() == false {
+                match self.policy {
+                    PageErrorPolicy::Strict => {
+                        return Err(OggError::ChecksumMismatch(page.page_sequence_no))
+                    }
+                    PageErrorPolicy::Skip => {
+                        self.lose_packets();
+                        continue;
+                    }
+                    PageErrorPolicy::Ignore => (),
+                }
+            }
+
+            if self.is_selected(&page) == false {
+                self.other_streams
+                    .entry(page.stream_serial_number)
+                    .or_insert_with(|| LogicalStream::new(page.stream_serial_number))
+                    .push_page(&page, self.keep_other_packets);
+                continue;
+            }
+            if page.header_type_flag.is_last_page() == true {
+                self.is_ended = true;
+            }
+
+            // Sequence numbers are only comparable within a logical stream.
+            // Bytes skipped to get to the page could have held packets,
+            // unless its sequence number follows on from the last page.
+            let serial = page.stream_serial_number;
+            let expected = match self.previous_page {
+                Some((previous_serial, sequence_no)) if previous_serial == serial => {
+                    Some(sequence_no.wrapping_add(1))
+                }
+                _ => None,
+            };
+            self.previous_page = Some((serial, page.page_sequence_no));
+            let lost = match expected {
+                Some(expected) if expected == page.page_sequence_no => false,
+                Some(expected) => match self.policy {
+                    PageErrorPolicy::Strict => {
+                        return Err(OggError::PageSequenceGap {
+                            expected,
+                            found: page.page_sequence_no,
+                        })
+                    }
+                    PageErrorPolicy::Skip => true,
+                    PageErrorPolicy::Ignore => bytes_discarded > 0,
+                },
+                None => bytes_discarded > 0,
+            };
+            if lost == true {
+                self.lose_packets();
+            }
+            let is_continued_packet = page.header_type_flag.is_continued_packet();
+            if is_continued_packet == false {
+                self.skip_continued_packet = false;
+            }
+            if is_continued_packet == true && self.skip_continued_packet == true {
+                // The packet started before a seek or a gap, so it can't be used
+            } else if is_continued_packet == true && self.partial_packet.is_empty() == true {
+                return Err(OggError::UnexpectedContinuation(page.page_sequence_no));
+            } else if is_continued_packet == false && self.partial_packet.is_empty() == false {
+                return Err(OggError::MissingContinuation(page.page_sequence_no));
+            }
+            self.page = Some(page);
+            self.segment = 0;
+            self.offset = 0;
+        }
+    }
+
+    /// Whether packets were lost just before the last packet returned,
+    /// because pages were damaged or missing.
+    pub fn follows_gap(&self) -> bool {
+        self.follows_gap
+    }
+
+    /// Whether the last packet returned was the first of a logical stream.
+    /// That packet starts the page flagged as the beginning of the stream,
+    /// which in a chained file follows the end of the stream before it.
+    pub fn starts_stream(&self) -> bool {
+        self.starts_stream
+    }
+
+    /// Whether `page` belongs to the logical stream being read, picking that
+    /// out from the first page of each stream until one is found.
+    fn is_selected(&mut self, page: &OggPage) -> bool {
+        let identify = match self.identify {
+            Some(identify) => identify,
+            None => return true,
+        };
+        match self.serial {
+            Some(serial) if serial == page.stream_serial_number => return true,
+            Some(_) if self.is_ended == false => return false,
+            _ => (),
+        }
+        if page.header_type_flag.is_first_page() == false {
+            return false;
+        }
+
+        // The first page of a logical stream holds just its first packet
+        let len = page.segment_table.iter().take_while(|b| **b == 255).count() + 1;
+        if len > page.segment_table.len() {
+            return false;
+        }
+        let packet_len = page.segment_table[..len].iter().map(|b| *b as usize).sum();
+        if identify(&page.data[..packet_len]) == false {
+            return false;
+        }
+        self.serial = Some(page.stream_serial_number);
+        self.is_ended = false;
+        true
+    }
+
+    /// Drop the packet in progress, along with the rest of it on the next
+    /// page, and note that packets were lost.
+    fn lose_packets(&mut self) {
+        self.partial_packet.clear();
+        self.skip_continued_packet = true;
+        self.packets_lost = true;
+    }
+
+    /// Serial number of the logical stream the last packet belongs to.
+    pub fn stream_serial_number(&self) -> Option<u32> {
+        self.page.as_ref().map(|page| page.stream_serial_number)
+    }
+
+    /// Granule position of the page the last packet ended on, if it was the
+    /// final packet to end on that page. Pages where no packet ends have a
+    /// granule position of -1, which is never returned.
+    pub fn granule_position(&self) -> Option<u64> {
+        let page = self.page.as_ref()?;
+        if self.ends_page() == false || page.absolute_granule_position == u64::MAX {
+            return None;
+        }
+        Some(page.absolute_granule_position)
+    }
+
+    /// Whether the last packet was the final packet of the logical stream.
+    pub fn is_last_packet(&self) -> bool {
+        match &self.page {
+            Some(page) => page.header_type_flag.is_last_page() == true && self.ends_page() == true,
+            None => false,
+        }
+    }
+
+    /// Whether no more packets end on the current page.
+    fn ends_page(&self) -> bool {
+        match &self.page {
+            Some(page) => page.segment_table[self.segment..]
+                .iter()
+                .all(|lacing_value| *lacing_value == 255),
+            None => true,
+        }
+    }
+}
+
+impl<R: Read + Seek> OggPacketReader<R> {
+    /// Total length of the stream in bytes, counted like `position`.
+    pub fn stream_len(&mut self) -> Result<u64, OggError> {
+        let start = self.start()?;
+        let end = self.scanner.reader.seek(SeekFrom::End(0))?;
+        self.restore_position()?;
+        Ok(end - start)
+    }
+
+    /// Continue reading from the page at byte `position`. The rest of a
+    /// packet continued from before that page is skipped.
+    pub fn seek_to_page(&mut self, position: u64) -> Result<(), OggError> {
+        let start = self.start()?;
+        self.scanner
+            .reader
+            .seek(SeekFrom::Start(start + position))?;
+        self.scanner.buffer.clear();
+        self.page = None;
+        self.segment = 0;
+        self.offset = 0;
+        self.partial_packet.clear();
+        self.position = position;
+        self.skip_continued_packet = true;
+        self.previous_page = None;
+        self.packets_lost = false;
+        self.follows_gap = false;
+        self.is_ended = false;
+        for stream in self.other_streams.values_mut() {
+            stream.partial_packet.clear();
+            stream.skip_continued_packet = true;
+        }
+        Ok(())
+    }
+
+    /// Search from byte `position` for the next page with a valid checksum
+    /// that starts before `end`, returning it with its byte offset. Use
+    /// `seek_to_page` or `restore_position` afterwards to carry on reading
+    /// packets.
+    pub fn find_page(
+        &mut self,
+        position: u64,
+        end: u64,
+    ) -> Result<Option<(u64, OggPage)>, OggError> {
+        let start = self.start()?;
+        let mut position = position;
+        let mut buffer = [0; 4096];
+        while position < end {
+            self.scanner
+                .reader
+                .seek(SeekFrom::Start(start + position))?;
+            let len = read_fully(&mut self.scanner.reader, &mut buffer)?;
+            let candidate = match buffer[..len].windows(4).position(|w| w == CAPTURE_PATTERN) {
+                Some(i) => position + i as u64,
+                None if len < buffer.len() => return Ok(None),
+                None => {
+                    position += (len - 3) as u64;
+                    continue;
+                }
+            };
+            if candidate >= end {
+                return Ok(None);
+            }
+
+            self.scanner
+                .reader
+                .seek(SeekFrom::Start(start + candidate))?;
+            match OggPage::read(&mut self.scanner.reader) {
+                Ok(Some(page)) if page.verify_crc() == true => return Ok(Some((candidate, page))),
+                Ok(_) => (),
+                Err(OggError::IOError(e)) if e.kind() != std::io::ErrorKind::UnexpectedEof => {
+                    return Err(e.into())
+                }
+                Err(_) => (), // Not a page after all
+            }
+            position = candidate + 1;
+        }
+        Ok(None)
+    }
+
+    /// Find the last page of the logical stream `serial` that has a granule
+    /// position, searching back from the end of the stream, and return it
+    /// with its byte offset. Reading packets
+    /// carries on from where it was afterwards.
+    pub fn find_last_page(&mut self, serial: u32) -> Result<Option<(u64, OggPage)>, OggError> {
+        let end = self.stream_len()?;
+        let mut chunk_end = end;
+        let mut chunk_len = 1 << 16;
+        let mut last_page = None;
+        while last_page.is_none() && chunk_end > 0 {
+            // Pages starting in the chunk are searched in order, and they can
+            // run on past its end
+            let chunk_start = chunk_end.saturating_sub(chunk_len);
+            let mut position = chunk_start;
+            while let Some((offset, page)) = self.find_page(position, chunk_end)? {
+                position = offset + page.byte_len() as u64;
+                if page.stream_serial_number == serial && page.absolute_granule_position != u64::MAX
+                {
+                    last_page = Some((offset, page));
+                }
+            }
+            chunk_end = chunk_start;
+            chunk_len *= 2;
+        }
+
+        self.restore_position()?;
+        Ok(last_page)
+    }
+
+    /// Move the underlying reader back to where reading packets left off.
+    pub fn restore_position(&mut self) -> Result<(), OggError> {
+        let start = self.start()?;
+        self.scanner
+            .reader
+            .seek(SeekFrom::Start(start + self.position))?;
+        self.scanner.buffer.clear();
+        Ok(())
+    }
+
+    /// Where the reader started, as an offset into the underlying reader.
+    fn start(&mut self) -> Result<u64, OggError> {
+        if let Some(start) = self.start {
+            return Ok(start);
+        }
+        let buffered = self.scanner.buffer.len() as u64;
+        let start = self.scanner.reader.stream_position()? - buffered - self.position;
+        self.start = Some(start);
+        Ok(start)
+    }
+}
+
+/// Like `read_exact`, but a short read is not an error. Returns the number of bytes read.
+fn read_fully<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut total = 0;
+    while total < buf.len() {
+        match reader.read(&mut buf[total..]) {
+            Ok(0) => break,
+            Ok(n) => total += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(total)
+}
+
+#[derive(Debug, Error)]
+pub enum OggError {
+    #[error("No capture pattern where a page should start")]
+    MissingCapturePattern,
+
+    #[error("Page {0} continues a packet but no packet is in progress")]
+    UnexpectedContinuation(u32),
+
+    #[error("Page {0} does not continue the packet in progress")]
+    MissingContinuation(u32),
+
+    #[error("Page {0} failed its checksum")]
+    ChecksumMismatch(u32),
+
+    #[error("Expected page {expected} but found page {found}")]
+    PageSequenceGap { expected: u32, found: u32 },
+
+    #[error("Stream ended in the middle of a packet")]
+    TruncatedPacket,
+
+    #[error(transparent)]
+    Deku(#[from] DekuError),
+
+    // Represents all cases of `std::io::Error`.
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -95,4 +859,355 @@ mod test {
         let ogg_page = OggPage::from_bytes((&raw_bytes, 0)).unwrap().1;
         assert_eq!(ogg_page.verify_crc(), false);
     }
+
+    #[test]
+    fn test_ogg_page_read() {
+        use std::io::Cursor;
+
+        let raw_bytes = [
+            0x4F, 0x67, 0x67, 0x53, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x4B, 0x86, 0x5C, 0x7D, 0x00, 0x00, 0x00, 0x00, 0xC1, 0xE3, 0xE7, 0xEF, 0x01, 0x1E,
+            0x01, 0x76, 0x6F, 0x72, 0x62, 0x69, 0x73, 0x00, 0x00, 0x00, 0x00, 0x01, 0x44, 0xAC,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x77, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0xB8, 0x01,
+        ];
+        let mut cursor = Cursor::new(&raw_bytes);
+        let ogg_page = OggPage::read(&mut cursor).unwrap().unwrap();
+        assert_eq!(ogg_page.page_sequence_no, 0);
+        assert_eq!(ogg_page.data, raw_bytes[28..]);
+        assert!(OggPage::read(&mut cursor).unwrap().is_none());
+
+        // Truncated in the middle of the header
+        let mut cursor = Cursor::new(&raw_bytes[..20]);
+        let err = OggPage::read(&mut cursor).unwrap_err();
+        assert!(
+            matches!(err, OggError::IOError(e) if e.kind() == std::io::ErrorKind::UnexpectedEof)
+        );
+
+        // Truncated in the middle of the data
+        let mut cursor = Cursor::new(&raw_bytes[..40]);
+        let err = OggPage::read(&mut cursor).unwrap_err();
+        assert!(
+            matches!(err, OggError::IOError(e) if e.kind() == std::io::ErrorKind::UnexpectedEof)
+        );
+    }
+
+    fn page_bytes(header_type_flag: u8, page_sequence_no: u32, segment_table: &[u8]) -> Vec<u8> {
+        let data_len = segment_table.iter().map(|b| *b as usize).sum::<usize>();
+        let data: Vec<u8> = (0..data_len).map(|i| i as u8).collect();
+        crate::test_stream::ogg_page(
+            header_type_flag,
+            0,
+            0,
+            page_sequence_no,
+            segment_table,
+            &data,
+        )
+    }
+
+    #[test]
+    fn test_ogg_packet_reader() {
+        use std::io::Cursor;
+
+        // Two packets in one page, then one packet split across two pages, then a packet of exactly 255 bytes
+        let input = [
+            page_bytes(0x02, 0, &[3, 0]),
+            page_bytes(0x00, 1, &[255, 255]),
+            page_bytes(0x01, 2, &[10, 255, 0]),
+        ]
+        .concat();
+        let mut packet_reader = OggPacketReader::new(Cursor::new(input));
+        assert_eq!(packet_reader.next_packet().unwrap(), Some(vec![0, 1, 2]));
+        assert_eq!(packet_reader.next_packet().unwrap(), Some(vec![]));
+        let packet = packet_reader.next_packet().unwrap().unwrap();
+        assert_eq!(packet.len(), 520);
+        assert_eq!(
+            packet[510..],
+            (0..10).map(|i| i as u8).collect::<Vec<_>>()[..]
+        );
+        let packet = packet_reader.next_packet().unwrap().unwrap();
+        assert_eq!(packet.len(), 255);
+        assert_eq!(packet[0], 10);
+        assert_eq!(packet_reader.next_packet().unwrap(), None);
+
+        // Stream ends with a packet in progress
+        let input = page_bytes(0x02, 0, &[255]);
+        let mut packet_reader = OggPacketReader::new(Cursor::new(input));
+        let err = packet_reader.next_packet().unwrap_err();
+        assert!(matches!(err, OggError::TruncatedPacket));
+
+        // Page claims to continue a packet but there isn't one
+        let input = page_bytes(0x01, 0, &[3]);
+        let mut packet_reader = OggPacketReader::new(Cursor::new(input));
+        let err = packet_reader.next_packet().unwrap_err();
+        assert!(matches!(err, OggError::UnexpectedContinuation(0)));
+
+        // Page doesn't continue the packet in progress
+        let input = [page_bytes(0x02, 0, &[255]), page_bytes(0x00, 1, &[3])].concat();
+        let mut packet_reader = OggPacketReader::new(Cursor::new(input));
+        let err = packet_reader.next_packet().unwrap_err();
+        assert!(matches!(err, OggError::MissingContinuation(1)));
+    }
+
+    #[test]
+    fn test_ogg_packet_reader_granule_position() {
+        use crate::test_stream::ogg_page;
+        use std::io::Cursor;
+
+        // Two packets end on the first page, the third spans the second page,
+        // whose granule position is -1, and ends on the last page
+        let input = [
+            ogg_page(0x02, 100, 0, 0, &[1, 1, 255], &[0; 257]),
+            ogg_page(0x01, u64::MAX, 0, 1, &[255], &[0; 255]),
+            ogg_page(0x05, 300, 0, 2, &[1], &[0]),
+        ]
+        .concat();
+        let mut packet_reader = OggPacketReader::new(Cursor::new(input));
+        assert_eq!(packet_reader.granule_position(), None);
+        assert_eq!(packet_reader.is_last_packet(), false);
+
+        // The granule position belongs to the last packet ending on the page
+        packet_reader.next_packet().unwrap().unwrap();
+        assert_eq!(packet_reader.granule_position(), None);
+        packet_reader.next_packet().unwrap().unwrap();
+        assert_eq!(packet_reader.granule_position(), Some(100));
+        assert_eq!(packet_reader.is_last_packet(), false);
+
+        let packet = packet_reader.next_packet().unwrap().unwrap();
+        assert_eq!(packet.len(), 511);
+        assert_eq!(packet_reader.granule_position(), Some(300));
+        assert_eq!(packet_reader.is_last_packet(), true);
+        assert_eq!(packet_reader.next_packet().unwrap(), None);
+    }
+
+    #[test]
+    fn test_ogg_page_scanner() {
+        use crate::test_stream::ogg_page;
+        use std::io::Cursor;
+
+        let page_0 = ogg_page(0x02, 0, 0, 0, &[3], &[1, 2, 3]);
+        let page_1 = ogg_page(0x00, 10, 0, 1, &[2], &[4, 5]);
+        let mut damaged = ogg_page(0x00, 20, 0, 2, &[1], &[6]);
+        damaged[28] = 7;
+
+        // Garbage, then a false capture pattern
+        let input = [b"junk".to_vec(), b"OggS\0\0".to_vec(), page_0.clone()].concat();
+        let mut scanner = OggPageScanner::new(Cursor::new(&input));
+        let err = scanner.read_page().unwrap_err();
+        assert!(matches!(err, OggError::MissingCapturePattern));
+        let page = scanner.scan_page().unwrap().unwrap();
+        assert_eq!(page.data, [1, 2, 3]);
+        assert_eq!(scanner.bytes_discarded(), 10);
+        assert!(scanner.read_page().unwrap().is_none());
+
+        // A page with a bad checksum, then enough garbage to need more than
+        // one read, and a page cut off at the end of the stream
+        let input = [
+            damaged.clone(),
+            page_0.clone(),
+            vec![0xAA; 4094],
+            page_1.clone(),
+            page_0[..20].to_vec(),
+        ]
+        .concat();
+        let mut scanner = OggPageScanner::new(Cursor::new(&input));
+        let page = scanner.scan_page().unwrap().unwrap();
+        assert_eq!(page.data, [1, 2, 3]);
+        assert_eq!(scanner.bytes_discarded(), damaged.len() as u64);
+        let page = scanner.scan_page().unwrap().unwrap();
+        assert_eq!(page.data, [4, 5]);
+        assert_eq!(scanner.bytes_discarded(), damaged.len() as u64 + 4094);
+        assert!(scanner.scan_page().unwrap().is_none());
+        assert_eq!(scanner.bytes_discarded(), damaged.len() as u64 + 4094 + 20);
+
+        // Reading pages in place doesn't check checksums
+        let mut scanner = OggPageScanner::new(Cursor::new(&damaged));
+        let page = scanner.read_page().unwrap().unwrap();
+        assert_eq!(page.data, [7]);
+        assert_eq!(scanner.bytes_discarded(), 0);
+    }
+
+    #[test]
+    fn test_ogg_packet_reader_resync() {
+        use crate::test_stream::ogg_page;
+        use std::io::Cursor;
+
+        // Garbage between pages in sequence loses nothing, even in the
+        // middle of a packet
+        let page_0 = ogg_page(0x02, 0, 0, 0, &[1, 255], &[0; 256]);
+        let page_1 = ogg_page(0x01, 0, 0, 1, &[10, 2], &[1; 12]);
+        let input = [page_0.clone(), vec![0x55; 100], page_1.clone()].concat();
+        let mut packet_reader = OggPacketReader::new(Cursor::new(input));
+        assert_eq!(packet_reader.next_packet().unwrap(), Some(vec![0]));
+        assert_eq!(packet_reader.next_packet().unwrap().unwrap().len(), 265);
+        assert_eq!(packet_reader.follows_gap(), false);
+        assert_eq!(packet_reader.next_packet().unwrap(), Some(vec![1; 2]));
+        assert_eq!(packet_reader.bytes_discarded(), 100);
+        assert_eq!(
+            packet_reader.position(),
+            (page_0.len() + 100 + page_1.len()) as u64
+        );
+        assert_eq!(packet_reader.next_packet().unwrap(), None);
+
+        // Garbage in place of a page. The rest of the packet in progress is
+        // dropped, whatever the policy.
+        let page_2 = ogg_page(0x01, 0, 0, 2, &[3], &[2; 3]);
+        let input = [page_0.clone(), vec![0x55; 100], page_2.clone()].concat();
+        for policy in [PageErrorPolicy::Skip, PageErrorPolicy::Ignore] {
+            let mut packet_reader = OggPacketReader::new(Cursor::new(&input));
+            packet_reader.set_page_error_policy(policy);
+            assert_eq!(packet_reader.next_packet().unwrap(), Some(vec![0]));
+            assert_eq!(packet_reader.follows_gap(), false);
+            assert_eq!(packet_reader.next_packet().unwrap(), None);
+        }
+        let mut packet_reader = OggPacketReader::new(Cursor::new(&input));
+        packet_reader.set_page_error_policy(PageErrorPolicy::Strict);
+        assert_eq!(packet_reader.next_packet().unwrap(), Some(vec![0]));
+        let err = packet_reader.next_packet().unwrap_err();
+        assert!(matches!(err, OggError::MissingCapturePattern));
+    }
+
+    #[test]
+    fn test_ogg_packet_reader_page_errors() {
+        use crate::test_stream::ogg_page;
+        use std::io::Cursor;
+
+        let pages = [
+            ogg_page(0x02, 0, 0, 0, &[1, 255], &[0; 256]),
+            ogg_page(0x01, 0, 0, 1, &[10, 1], &[1; 11]),
+            ogg_page(0x00, 0, 0, 2, &[2], &[2; 2]),
+            ogg_page(0x00, 0, 0, 3, &[3], &[3; 3]),
+        ];
+        let read_all = |input: &[u8], policy| {
+            let mut packet_reader = OggPacketReader::new(Cursor::new(input));
+            packet_reader.set_page_error_policy(policy);
+            let mut packets = Vec::new();
+            while let Some(packet) = packet_reader.next_packet()? {
+                packets.push((packet.len(), packet_reader.follows_gap()));
+            }
+            Ok::<_, OggError>(packets)
+        };
+
+        // A damaged checksum
+        let mut damaged = pages.clone();
+        damaged[1][35] ^= 0xFF;
+        let input = damaged.concat();
+        let err = read_all(&input, PageErrorPolicy::Strict).unwrap_err();
+        assert!(matches!(err, OggError::ChecksumMismatch(1)));
+        let packets = read_all(&input, PageErrorPolicy::Skip).unwrap();
+        assert_eq!(packets, [(1, false), (2, true), (3, false)]);
+        let packets = read_all(&input, PageErrorPolicy::Ignore).unwrap();
+        assert_eq!(
+            packets,
+            [(1, false), (265, false), (1, false), (2, false), (3, false)]
+        );
+
+        // A missing page
+        let input = [&pages[..2], &pages[3..]].concat().concat();
+        let err = read_all(&input, PageErrorPolicy::Strict).unwrap_err();
+        assert!(matches!(
+            err,
+            OggError::PageSequenceGap {
+                expected: 2,
+                found: 3
+            }
+        ));
+        let packets = read_all(&input, PageErrorPolicy::Skip).unwrap();
+        assert_eq!(packets, [(1, false), (265, false), (1, false), (3, true)]);
+        let packets = read_all(&input, PageErrorPolicy::Ignore).unwrap();
+        assert_eq!(packets, [(1, false), (265, false), (1, false), (3, false)]);
+
+        // Pages of another logical stream have their own sequence numbers
+        let other = ogg_page(0x02, 0, 1, 0, &[4], &[4; 4]);
+        let input = [&pages[..], &[other]].concat().concat();
+        let packets = read_all(&input, PageErrorPolicy::Strict).unwrap();
+        assert_eq!(packets.len(), 6);
+    }
+
+    #[test]
+    fn test_ogg_packet_reader_starts_stream() {
+        use crate::test_stream::ogg_page;
+        use std::io::Cursor;
+
+        // Two chained streams, the second with its first packet spanning
+        // two pages
+        let input = [
+            ogg_page(0x02, 0, 0, 0, &[1], &[0]),
+            ogg_page(0x04, 0, 0, 1, &[1, 1], &[1; 2]),
+            ogg_page(0x02, 0, 1, 0, &[255], &[2; 255]),
+            ogg_page(0x01, 0, 1, 1, &[1, 1], &[3; 2]),
+        ]
+        .concat();
+        let mut packet_reader = OggPacketReader::new(Cursor::new(input));
+        let mut starts_stream = Vec::new();
+        while let Some(packet) = packet_reader.next_packet().unwrap() {
+            starts_stream.push((packet.len(), packet_reader.starts_stream()));
+        }
+        assert_eq!(
+            starts_stream,
+            [(1, true), (1, false), (1, false), (256, true), (1, false)]
+        );
+    }
+
+    #[test]
+    fn test_ogg_packet_reader_select_stream() {
+        use crate::test_stream::ogg_page;
+        use std::io::Cursor;
+
+        // The stream wanted is the second one to start. Both have a packet
+        // spanning two pages, and their pages are interleaved.
+        let input = [
+            ogg_page(0x02, 0, 7, 0, &[5], b"other"),
+            ogg_page(0x02, 0, 3, 0, &[4], b"want"),
+            ogg_page(0x00, 0, 7, 1, &[1, 255], &[1; 256]),
+            ogg_page(0x00, 0, 3, 1, &[255], &[2; 255]),
+            ogg_page(0x01, 0, 7, 2, &[1, 1], &[3; 2]),
+            ogg_page(0x01, 0, 3, 2, &[2], &[4; 2]),
+            ogg_page(0x04, 0, 7, 3, &[1], &[5]),
+            ogg_page(0x04, 0, 3, 3, &[1], &[6]),
+        ]
+        .concat();
+        for keep in [true, false] {
+            let mut packet_reader = OggPacketReader::new(Cursor::new(&input));
+            packet_reader.set_page_error_policy(PageErrorPolicy::Strict);
+            packet_reader.select_stream(|packet| packet.starts_with(b"want"));
+            packet_reader.set_keep_other_packets(keep);
+            let mut packets = Vec::new();
+            while let Some(packet) = packet_reader.next_packet().unwrap() {
+                packets.push(packet);
+                assert_eq!(packet_reader.stream_serial_number(), Some(3));
+            }
+            assert_eq!(
+                packets,
+                [
+                    b"want".to_vec(),
+                    [vec![2; 255], vec![4; 2]].concat(),
+                    vec![6]
+                ]
+            );
+
+            let streams: Vec<_> = packet_reader
+                .other_streams()
+                .map(|stream| (stream.serial(), stream.first_packet(), stream.is_ended()))
+                .collect();
+            assert_eq!(streams, [(7, Some(&b"other"[..]), true)]);
+            let stream = packet_reader.other_stream_mut(7).unwrap();
+            let mut packets = Vec::new();
+            while let Some(packet) = stream.next_packet() {
+                packets.push(packet.len());
+            }
+            if keep == true {
+                assert_eq!(packets, [5, 1, 256, 1, 1]);
+            } else {
+                assert_eq!(packets, []);
+            }
+            assert!(packet_reader.other_stream_mut(3).is_none());
+        }
+
+        // Nothing is read if no stream is wanted
+        let mut packet_reader = OggPacketReader::new(Cursor::new(&input));
+        packet_reader.select_stream(|_| false);
+        assert_eq!(packet_reader.next_packet().unwrap(), None);
+        assert_eq!(packet_reader.other_streams().count(), 2);
+    }
 }
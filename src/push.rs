@@ -0,0 +1,254 @@
+use crate::{
+    decoder::{self, DecoderEvent, VorbisDecoder},
+    ogg::{OggError, OggPacketReader},
+    vorbis::{CommentHeader, IdHeader, VorbisError},
+};
+use std::{
+    collections::VecDeque,
+    io::{ErrorKind, Read},
+};
+
+/// Reads the bytes fed so far, and would block when it runs out of them
+/// before the input is finished.
+#[derive(Debug, Default)]
+struct PushReader {
+    bytes: VecDeque<u8>,
+    /// Whether no more bytes will be fed
+    is_finished: bool,
+}
+
+impl Read for PushReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.bytes.is_empty() == true && self.is_finished == false {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        self.bytes.read(buf)
+    }
+}
+
+#[allow(clippy::large_enum_variant)] // There's only ever one of these
+enum State {
+    /// Reading the header packets, which are kept until all three are in
+    Headers {
+        packet_reader: OggPacketReader<PushReader>,
+        header_packets: Vec<Vec<u8>>,
+    },
+    Decoding(VorbisDecoder<PushReader>),
+}
+
+/// Decodes an Ogg Vorbis stream from bytes pushed in as they arrive, in
+/// chunks of any size, such as from a socket. Partial pages and packets are
+/// kept between calls, and bytes are dropped once they've been read.
+pub struct PushDecoder {
+    state: State,
+}
+
+impl PushDecoder {
+    pub fn new() -> Self {
+        Self {
+            state: State::Headers {
+                packet_reader: decoder::header_packet_reader(PushReader::default()),
+                header_packets: Vec::new(),
+            },
+        }
+    }
+
+    /// Add the next bytes of the stream. Fails with `InputFinished` after
+    /// `finish`.
+    pub fn feed(&mut self, bytes: &[u8]) -> Result<(), VorbisError> {
+        let input = self.input();
+        if input.is_finished == true {
+            return Err(VorbisError::InputFinished);
+        }
+        input.bytes.extend(bytes);
+        Ok(())
+    }
+
+    /// Mark the end of the stream, after which no more bytes can be fed.
+    pub fn finish(&mut self) {
+        self.input().is_finished = true;
+    }
+
+    fn input(&mut self) -> &mut PushReader {
+        match &mut self.state {
+            State::Headers { packet_reader, .. } => packet_reader.get_mut(),
+            State::Decoding(decoder) => decoder.get_mut(),
+        }
+    }
+
+    /// The identification header, once it's been read.
+    pub fn id_header(&self) -> Option<&IdHeader> {
+        match &self.state {
+            State::Headers { .. } => None,
+            State::Decoding(decoder) => Some(decoder.id_header()),
+        }
+    }
+
+    /// The comment header, once it's been read.
+    pub fn comment_header(&self) -> Option<&CommentHeader> {
+        match &self.state {
+            State::Headers { .. } => None,
+            State::Decoding(decoder) => Some(decoder.comment_header()),
+        }
+    }
+
+    /// Like `VorbisDecoder::next_pcm`, but fails with `NeedMoreData` when the
+    /// bytes fed so far run out, rather than at the end of the stream.
+    /// Feeding more bytes then lets decoding carry on.
+    pub fn next_pcm(&mut self) -> Result<Option<Vec<Vec<f32>>>, VorbisError> {
+        let result = self.decoder()?.next_pcm();
+        result.map_err(need_more_data)
+    }
+
+    /// Like `VorbisDecoder::next_event`, but fails with `NeedMoreData` when
+    /// the bytes fed so far run out.
+    pub fn next_event(&mut self) -> Result<Option<DecoderEvent>, VorbisError> {
+        let result = self.decoder()?.next_event();
+        result.map_err(need_more_data)
+    }
+
+    /// The decoder, once the header packets are all in.
+    fn decoder(&mut self) -> Result<&mut VorbisDecoder<PushReader>, VorbisError> {
+        if let State::Headers {
+            packet_reader,
+            header_packets,
+        } = &mut self.state
+        {
+            decoder::read_header_packets(packet_reader, header_packets).map_err(need_more_data)?;
+            let headers = decoder::parse_headers(header_packets)?;
+            let packet_reader =
+                std::mem::replace(packet_reader, OggPacketReader::new(PushReader::default()));
+            let decoder = VorbisDecoder::from_headers(packet_reader, headers);
+            self.state = State::Decoding(decoder);
+        }
+        match &mut self.state {
+            State::Headers { .. } => unreachable!(),
+            State::Decoding(decoder) => Ok(decoder),
+        }
+    }
+}
+
+impl Default for PushDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Turn running out of bytes fed so far into `NeedMoreData`.
+fn need_more_data(e: VorbisError) -> VorbisError {
+    match e {
+        VorbisError::Ogg(OggError::IOError(e)) if e.kind() == ErrorKind::WouldBlock => {
+            VorbisError::NeedMoreData
+        }
+        e => e,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_stream::{self, Rng, StreamConfig};
+    use std::io::Cursor;
+
+    /// Samples from each event, with changes of stream as empty vectors
+    fn samples(event: DecoderEvent) -> Vec<Vec<f32>> {
+        match event {
+            DecoderEvent::Pcm(pcm) => pcm,
+            DecoderEvent::StreamChanged { .. } | DecoderEvent::PacketsLost => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_push_decoder() {
+        let (single, _) = test_stream::build(&StreamConfig::default());
+        let (trimmed, _) = test_stream::build(&StreamConfig {
+            channels: 2,
+            start_trim: 50,
+            end_trim: 10,
+            ..Default::default()
+        });
+        let (second, _) = test_stream::build(&StreamConfig {
+            channels: 2,
+            serial: 0x0BAD_CAFE,
+            start_granule: 500,
+            ..Default::default()
+        });
+        let chained = [single.clone(), trimmed.clone(), second].concat();
+
+        let mut rng = Rng::new(9);
+        for input in [single, trimmed, chained] {
+            let mut decoder = VorbisDecoder::new(Cursor::new(input.clone())).unwrap();
+            let mut linear = Vec::new();
+            while let Some(event) = decoder.next_event().unwrap() {
+                linear.push(samples(event));
+            }
+
+            let mut decoder = PushDecoder::new();
+            assert!(matches!(decoder.next_pcm(), Err(VorbisError::NeedMoreData)));
+            assert!(decoder.id_header().is_none());
+
+            // Chunks from one byte up to several pages long
+            let mut events = Vec::new();
+            let mut position = 0;
+            while position < input.len() {
+                let len = match rng.below(3) {
+                    0 => 1,
+                    1 => 1 + rng.below(50),
+                    _ => 1 + rng.below(1000),
+                } as usize;
+                let end = (position + len).min(input.len());
+                decoder.feed(&input[position..end]).unwrap();
+                position = end;
+                loop {
+                    match decoder.next_event() {
+                        Ok(Some(event)) => events.push(samples(event)),
+                        Ok(None) => panic!("stream ended early"),
+                        Err(VorbisError::NeedMoreData) => break,
+                        Err(e) => panic!("{}", e),
+                    }
+                }
+            }
+            decoder.finish();
+            while let Some(event) = decoder.next_event().unwrap() {
+                events.push(samples(event));
+            }
+            assert_eq!(events, linear);
+        }
+    }
+
+    #[test]
+    fn test_push_decoder_finished_early() {
+        let (input, _) = test_stream::build(&StreamConfig::default());
+        let mut decoder = PushDecoder::new();
+        decoder.feed(&input[..100]).unwrap();
+        assert!(matches!(decoder.next_pcm(), Err(VorbisError::NeedMoreData)));
+        decoder.finish();
+        assert!(matches!(
+            decoder.next_pcm(),
+            Err(VorbisError::Ogg(OggError::IOError(e))) if e.kind() == ErrorKind::UnexpectedEof
+        ));
+        assert!(matches!(
+            decoder.feed(&input[100..]),
+            Err(VorbisError::InputFinished)
+        ));
+    }
+
+    #[test]
+    fn test_push_decoder_send() {
+        let (input, _) = test_stream::build(&StreamConfig::default());
+        let mut decoder = PushDecoder::new();
+        decoder.feed(&input).unwrap();
+        decoder.next_pcm().unwrap();
+
+        // Decoding carries on in another thread
+        let handle = std::thread::spawn(move || {
+            decoder.finish();
+            let mut packets = 0;
+            while decoder.next_pcm().unwrap().is_some() {
+                packets += 1;
+            }
+            packets
+        });
+        assert!(handle.join().unwrap() > 0);
+    }
+}
@@ -1,3 +1,8 @@
+use crate::{
+    bit_reader::PacketBitReader,
+    codebook::{Codebook, CodebookError},
+    util::{self, UnexpectedEof},
+};
 use bitstream_io::{BitRead, BitReader};
 use thiserror::Error;
 
@@ -9,7 +14,6 @@ pub struct Residue {
     partition_size: u32,
     classifications: u8,
     classbook: u8,
-    cascade: Vec<u8>,
     books: Vec<Vec<Option<u8>>>,
 }
 
@@ -59,11 +63,6 @@ impl Residue {
             })
             .collect::<Result<_, _>>()?;
 
-        // TODO: validate:
-        // Any codebook number greater than the maximum numbered codebook set up in this stream also renders the stream undecodable.
-        // All codebooks in array [residue_books] are required to have a value mapping.
-        // The presence of codebook in array [residue_books] without a value mapping (maptype equals zero) renders the stream undecodable.
-
         Ok(Self {
             residue_type,
             begin,
@@ -71,10 +70,174 @@ impl Residue {
             partition_size,
             classifications,
             classbook,
-            cascade,
             books,
         })
     }
+
+    /// Check that the classbook and every residue book is one of `codebooks`,
+    /// and that the residue books have a value mapping to read vectors with.
+    pub fn validate(&self, codebooks: &[Codebook]) -> Result<(), ResidueError> {
+        if self.classbook as usize >= codebooks.len() {
+            return Err(ResidueError::CodebookOutOfRange(self.classbook));
+        }
+        for &book in self.books.iter().flatten().flatten() {
+            let codebook = codebooks
+                .get(book as usize)
+                .ok_or(ResidueError::CodebookOutOfRange(book))?;
+            if codebook.lookup_type() == 0 {
+                return Err(ResidueError::NoValueMapping(book));
+            }
+        }
+        Ok(())
+    }
+
+    /// Decode the residue vectors for the channels in one submap of an audio
+    /// packet. `n` is half the current blocksize and `do_not_decode` has one
+    /// entry per channel; channels flagged there are left as all zeros.
+    pub fn decode_packet(
+        &self,
+        reader: &mut PacketBitReader,
+        codebooks: &[Codebook],
+        n: usize,
+        do_not_decode: &[bool],
+    ) -> Result<Vec<Vec<f32>>, ResidueError> {
+        let channels = do_not_decode.len();
+        if self.residue_type == 2 {
+            // Format 2 decodes all channels interleaved into a single vector,
+            // which is only skipped if every channel is flagged
+            let mut vectors = vec![vec![0.0; n]; channels];
+            if do_not_decode.iter().all(|flag| *flag == true) {
+                return Ok(vectors);
+            }
+
+            let mut interleaved = vec![vec![0.0; n * channels]];
+            self.decode_vectors(reader, codebooks, &mut interleaved, &[false])?;
+            for (i, value) in interleaved[0].iter().enumerate() {
+                vectors[i % channels][i / channels] = *value;
+            }
+            Ok(vectors)
+        } else {
+            let mut vectors = vec![vec![0.0; n]; channels];
+            self.decode_vectors(reader, codebooks, &mut vectors, do_not_decode)?;
+            Ok(vectors)
+        }
+    }
+
+    fn decode_vectors(
+        &self,
+        reader: &mut PacketBitReader,
+        codebooks: &[Codebook],
+        vectors: &mut [Vec<f32>],
+        do_not_decode: &[bool],
+    ) -> Result<(), ResidueError> {
+        // Running out of packet data while reading the residue is not an
+        // error; whatever hasn't been decoded yet remains zero.
+        util::eof_as_none(self.decode_partitions(reader, codebooks, vectors, do_not_decode))?;
+        Ok(())
+    }
+
+    fn decode_partitions(
+        &self,
+        reader: &mut PacketBitReader,
+        codebooks: &[Codebook],
+        vectors: &mut [Vec<f32>],
+        do_not_decode: &[bool],
+    ) -> Result<(), ResidueError> {
+        let actual_size = vectors.first().map_or(0, |v| v.len());
+        let limit_residue_begin = (self.begin as usize).min(actual_size);
+        let limit_residue_end = (self.end as usize).min(actual_size);
+        let partition_size = self.partition_size as usize;
+
+        let classbook = &codebooks[self.classbook as usize];
+        let classwords_per_codeword = classbook.dimensions() as usize;
+        let n_to_read = limit_residue_end.saturating_sub(limit_residue_begin);
+        let partitions_to_read = n_to_read / partition_size;
+        if partitions_to_read == 0 {
+            return Ok(());
+        }
+        if classwords_per_codeword == 0 {
+            return Err(ResidueError::ZeroDimensionClassbook(self.classbook));
+        }
+
+        let classifications_len = partitions_to_read + classwords_per_codeword;
+        let mut classifications = vec![vec![0; classifications_len]; vectors.len()];
+        for pass in 0..8 {
+            let mut partition_count = 0;
+            while partition_count < partitions_to_read {
+                if pass == 0 {
+                    for (j, classifications) in classifications.iter_mut().enumerate() {
+                        if do_not_decode[j] == true {
+                            continue;
+                        }
+                        let mut temp = classbook.read_scalar(reader)?;
+                        for i in (0..classwords_per_codeword).rev() {
+                            classifications[i + partition_count] =
+                                temp % self.classifications as u32;
+                            temp /= self.classifications as u32;
+                        }
+                    }
+                }
+
+                for _ in 0..classwords_per_codeword {
+                    if partition_count >= partitions_to_read {
+                        break;
+                    }
+                    for (j, vector) in vectors.iter_mut().enumerate() {
+                        if do_not_decode[j] == true {
+                            continue;
+                        }
+                        let vqclass = classifications[j][partition_count] as usize;
+                        if let Some(vqbook) = self.books[vqclass][pass] {
+                            let offset = limit_residue_begin + partition_count * partition_size;
+                            let partition = &mut vector[offset..offset + partition_size];
+                            self.decode_partition(reader, &codebooks[vqbook as usize], partition)?;
+                        }
+                    }
+                    partition_count += 1;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn decode_partition(
+        &self,
+        reader: &mut PacketBitReader,
+        codebook: &Codebook,
+        partition: &mut [f32],
+    ) -> Result<(), ResidueError> {
+        let dimensions = codebook.dimensions() as usize;
+        if dimensions == 0 {
+            return Err(ResidueError::ZeroDimensionCodebook);
+        }
+
+        if self.residue_type == 0 {
+            // Vector elements are interleaved through the partition
+            let step = partition.len() / dimensions;
+            for i in 0..step {
+                let entry_temp = codebook.read_vector(reader)?;
+                for (j, value) in entry_temp.iter().enumerate() {
+                    partition[i + j * step] += value;
+                }
+            }
+        } else {
+            // Vector elements are concatenated through the partition
+            let mut i = 0;
+            while i < partition.len() {
+                let entry_temp = codebook.read_vector(reader)?;
+                for value in entry_temp {
+                    if i >= partition.len() {
+                        break;
+                    }
+                    partition[i] += value;
+                    i += 1;
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Debug, Error)]
@@ -82,15 +245,216 @@ pub enum ResidueError {
     #[error("Invalid residue type: {0}")]
     InvalidResidueType(u16),
 
+    #[error("Codebook {0} is not configured")]
+    CodebookOutOfRange(u8),
+
+    #[error("Residue book {0} has no value mapping")]
+    NoValueMapping(u8),
+
+    #[error("Residue classbook {0} has zero dimensions")]
+    ZeroDimensionClassbook(u8),
+
+    #[error("Residue codebook has zero dimensions")]
+    ZeroDimensionCodebook,
+
+    #[error(transparent)]
+    Codebook(#[from] CodebookError),
+
     // Represents all cases of `std::io::Error`.
     #[error(transparent)]
     IOError(#[from] std::io::Error),
 }
 
+impl UnexpectedEof for ResidueError {
+    fn is_unexpected_eof(&self) -> bool {
+        match self {
+            Self::Codebook(e) => e.is_unexpected_eof(),
+            Self::IOError(e) => e.is_unexpected_eof(),
+            _ => false,
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use bitstream_io::{BitWrite, BitWriter, LittleEndian};
+    use std::io::Cursor;
+
+    fn test_codebooks() -> Vec<Codebook> {
+        use crate::test_stream;
+
+        // A classbook whose entry is the classification, and a VQ book where
+        // entry 0 is [0.5, 1.25] and entry 1 is [1.0, 2.5]
+        [
+            test_stream::codebook(1, &[1, 1], None),
+            test_stream::codebook(2, &[1, 1], Some((2, &[8, 9, 10, 12]))),
+        ]
+        .iter()
+        .map(|input| {
+            let mut cursor = Cursor::new(input);
+            let mut reader = BitReader::endian(&mut cursor, LittleEndian);
+            Codebook::decode(&mut reader).unwrap()
+        })
+        .collect()
+    }
+
+    /// Two partitions of 4 values. Class 0 is silent and class 1 has one pass.
+    fn test_residue(residue_type: u16) -> Residue {
+        let mut books = vec![vec![None; 8]; 2];
+        books[1][0] = Some(1);
+        Residue {
+            residue_type,
+            begin: 0,
+            end: 8,
+            partition_size: 4,
+            classifications: 2,
+            classbook: 0,
+            books,
+        }
+    }
 
     #[test]
-    fn test_() {}
+    fn test_residue_validate() {
+        let codebooks = test_codebooks();
+        let mut residue = test_residue(0);
+        assert!(residue.validate(&codebooks).is_ok());
+
+        // The classbook needs no value mapping, but the residue books do
+        residue.books[1][0] = Some(0);
+        assert!(matches!(
+            residue.validate(&codebooks),
+            Err(ResidueError::NoValueMapping(0))
+        ));
+        residue.books[1][0] = Some(2);
+        assert!(matches!(
+            residue.validate(&codebooks),
+            Err(ResidueError::CodebookOutOfRange(2))
+        ));
+
+        let mut residue = test_residue(0);
+        residue.classbook = 5;
+        assert!(matches!(
+            residue.validate(&codebooks),
+            Err(ResidueError::CodebookOutOfRange(5))
+        ));
+    }
+
+    /// Class 1 for the first partition, holding entries 0 and 1, then class 0
+    /// for the second
+    fn test_packet() -> Vec<u8> {
+        let mut writer = BitWriter::endian(Vec::new(), LittleEndian);
+        for bit in [true, false, true, false] {
+            writer.write_bit(bit).unwrap();
+        }
+        writer.byte_align().unwrap();
+        writer.into_writer()
+    }
+
+    fn decode_packet(
+        residue: &Residue,
+        packet: &[u8],
+        n: usize,
+        do_not_decode: &[bool],
+    ) -> Vec<Vec<f32>> {
+        let mut reader = PacketBitReader::new(packet);
+        residue
+            .decode_packet(&mut reader, &test_codebooks(), n, do_not_decode)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_residue_decode() {
+        let mut writer = BitWriter::endian(Vec::new(), LittleEndian);
+        writer.write(16, 1).unwrap(); // Residue type
+        writer.write(24, 8).unwrap(); // Begin
+        writer.write(24, 40).unwrap(); // End
+        writer.write(24, 15).unwrap(); // Partition size - 1
+        writer.write(6, 1).unwrap(); // Classifications - 1
+        writer.write(8, 0).unwrap(); // Classbook
+        writer.write(3, 0).unwrap(); // Class 0 has no books
+        writer.write_bit(false).unwrap();
+        writer.write(3, 1).unwrap(); // Class 1 has books in passes 0 and 3
+        writer.write_bit(true).unwrap();
+        writer.write(5, 1).unwrap();
+        writer.write(8, 1).unwrap();
+        writer.write(8, 2).unwrap();
+        writer.byte_align().unwrap();
+        let input = writer.into_writer();
+        let mut cursor = Cursor::new(&input);
+        let mut reader = BitReader::endian(&mut cursor, LittleEndian);
+        let residue = Residue::decode(&mut reader).unwrap();
+        assert_eq!(residue.residue_type, 1);
+        assert_eq!(residue.begin, 8);
+        assert_eq!(residue.end, 40);
+        assert_eq!(residue.partition_size, 16);
+        assert_eq!(residue.classifications, 2);
+        assert_eq!(residue.books[0], vec![None; 8]);
+        assert_eq!(
+            residue.books[1],
+            vec![Some(1), None, None, Some(2), None, None, None, None]
+        );
+
+        let input = [3, 0];
+        let mut cursor = Cursor::new(input);
+        let mut reader = BitReader::endian(&mut cursor, LittleEndian);
+        assert!(matches!(
+            Residue::decode(&mut reader),
+            Err(ResidueError::InvalidResidueType(3))
+        ));
+    }
+
+    #[test]
+    fn test_residue_decode_packet() {
+        let packet = test_packet();
+
+        // Format 0 interleaves each vector through the partition
+        let vectors = decode_packet(&test_residue(0), &packet, 8, &[false]);
+        assert_eq!(vectors, vec![vec![0.5, 1.0, 1.25, 2.5, 0.0, 0.0, 0.0, 0.0]]);
+
+        // Format 1 concatenates them
+        let vectors = decode_packet(&test_residue(1), &packet, 8, &[false]);
+        assert_eq!(vectors, vec![vec![0.5, 1.25, 1.0, 2.5, 0.0, 0.0, 0.0, 0.0]]);
+
+        // Format 2 decodes a single vector, then deinterleaves the channels
+        let vectors = decode_packet(&test_residue(2), &packet, 4, &[false, false]);
+        assert_eq!(
+            vectors,
+            vec![vec![0.5, 1.0, 0.0, 0.0], vec![1.25, 2.5, 0.0, 0.0]]
+        );
+
+        // Values outside of begin and end stay zero
+        let mut residue = test_residue(1);
+        residue.begin = 4;
+        residue.end = 12;
+        let vectors = decode_packet(&residue, &packet, 16, &[false]);
+        let mut expected = vec![0.0; 16];
+        expected[4..8].copy_from_slice(&[0.5, 1.25, 1.0, 2.5]);
+        assert_eq!(vectors, vec![expected]);
+
+        // Running out of data leaves the rest of the vector zero
+        let vectors = decode_packet(&test_residue(1), &[], 8, &[false]);
+        assert_eq!(vectors, vec![vec![0.0; 8]]);
+    }
+
+    #[test]
+    fn test_residue_do_not_decode() {
+        let packet = test_packet();
+
+        // Flagged channels read no data
+        let vectors = decode_packet(&test_residue(1), &packet, 8, &[true, false]);
+        assert_eq!(
+            vectors,
+            vec![vec![0.0; 8], vec![0.5, 1.25, 1.0, 2.5, 0.0, 0.0, 0.0, 0.0]]
+        );
+
+        // Format 2 only skips decoding if every channel is flagged
+        let vectors = decode_packet(&test_residue(2), &packet, 4, &[true, false]);
+        assert_eq!(
+            vectors,
+            vec![vec![0.5, 1.0, 0.0, 0.0], vec![1.25, 2.5, 0.0, 0.0]]
+        );
+        let vectors = decode_packet(&test_residue(2), &packet, 4, &[true, true]);
+        assert_eq!(vectors, vec![vec![0.0; 4]; 2]);
+    }
 }
@@ -0,0 +1,601 @@
+//! Builds small but complete Ogg Vorbis streams for tests. The setup header
+//! exercises two floor configurations of either type, all three residue
+//! formats, both vector lookup types, length ordered codebooks and channel
+//! coupling, and audio packets are filled with pseudo-random bits so every
+//! decode stage sees varied input.
+
+use bitstream_io::{BitWrite, BitWriter, LittleEndian};
+use crc_any::CRCu32;
+
+pub const BLOCKSIZE_0_EXP: u8 = 8;
+pub const BLOCKSIZE_1_EXP: u8 = 11;
+
+#[derive(Debug, Clone)]
+pub struct StreamConfig {
+    pub channels: u8,
+    pub sample_rate: u32,
+    pub serial: u32,
+    pub seed: u64,
+    /// Block flag of each audio packet
+    pub blocks: Vec<bool>,
+    /// Maximum number of segments in each audio page
+    pub segments_per_page: usize,
+    /// Number of samples to trim from the end via the final granule position
+    pub end_trim: u64,
+    /// Granule position of the start of the stream
+    pub start_granule: u64,
+    /// Number of samples to trim from the start by lowering every granule
+    /// position. Must not be more than the first audio page holds.
+    pub start_trim: u64,
+    /// Floor type used by both floor configurations
+    pub floor_type: u16,
+}
+
+impl Default for StreamConfig {
+    fn default() -> Self {
+        Self {
+            channels: 1,
+            sample_rate: 44100,
+            serial: 0x1234_5678,
+            seed: 1,
+            blocks: vec![false, false, true, true, false, true, false, false],
+            segments_per_page: 4,
+            end_trim: 0,
+            start_granule: 0,
+            start_trim: 0,
+            floor_type: 1,
+        }
+    }
+}
+
+/// A simple xorshift generator, so streams are reproducible.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+pub fn blocksize(blockflag: bool) -> u64 {
+    if blockflag == true {
+        1 << BLOCKSIZE_1_EXP
+    } else {
+        1 << BLOCKSIZE_0_EXP
+    }
+}
+
+/// Number of finished samples produced by each audio packet.
+pub fn packet_sample_counts(blocks: &[bool]) -> Vec<u64> {
+    let mut counts = Vec::with_capacity(blocks.len());
+    for (i, &blockflag) in blocks.iter().enumerate() {
+        if i == 0 {
+            counts.push(0);
+        } else {
+            counts.push(blocksize(blocks[i - 1]) / 4 + blocksize(blockflag) / 4);
+        }
+    }
+    counts
+}
+
+pub fn id_header(channels: u8, sample_rate: u32) -> Vec<u8> {
+    let mut packet = vec![1];
+    packet.extend_from_slice(b"vorbis");
+    packet.extend_from_slice(&0u32.to_le_bytes());
+    packet.push(channels);
+    packet.extend_from_slice(&sample_rate.to_le_bytes());
+    packet.extend_from_slice(&0i32.to_le_bytes());
+    packet.extend_from_slice(&128_000i32.to_le_bytes());
+    packet.extend_from_slice(&0i32.to_le_bytes());
+    packet.push(BLOCKSIZE_1_EXP << 4 | BLOCKSIZE_0_EXP);
+    packet.push(1);
+    packet
+}
+
+pub fn comment_header(vendor: &str, comments: &[&str]) -> Vec<u8> {
+    let mut packet = vec![3];
+    packet.extend_from_slice(b"vorbis");
+    packet.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    packet.extend_from_slice(vendor.as_bytes());
+    packet.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for comment in comments {
+        packet.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        packet.extend_from_slice(comment.as_bytes());
+    }
+    packet.push(1);
+    packet
+}
+
+fn float32_pack(mantissa: i32, exponent: i32) -> u32 {
+    let sign = if mantissa < 0 { 0x8000_0000 } else { 0 };
+    sign | ((exponent + 788) as u32) << 21 | mantissa.unsigned_abs()
+}
+
+fn ilog(x: u32) -> u32 {
+    32 - x.leading_zeros()
+}
+
+struct Writer(BitWriter<Vec<u8>, LittleEndian>);
+
+impl Writer {
+    fn new() -> Self {
+        Self(BitWriter::endian(Vec::new(), LittleEndian))
+    }
+
+    fn bits(&mut self, bits: u32, value: u32) {
+        self.0.write(bits, value).unwrap();
+    }
+
+    fn flag(&mut self, value: bool) {
+        self.0.write_bit(value).unwrap();
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.0.byte_align().unwrap();
+        self.0.into_writer()
+    }
+
+    fn codeword_lengths(&mut self, dimensions: u32, lengths: &[u32]) {
+        for b in [0x42, 0x43, 0x56] {
+            self.bits(8, b);
+        }
+        self.bits(16, dimensions);
+        self.bits(24, lengths.len() as u32);
+        self.flag(false); // Ordered
+        self.flag(false); // Sparse
+        for length in lengths {
+            self.bits(5, length - 1);
+        }
+    }
+
+    /// Codeword lengths in ascending order, written as the number of
+    /// codewords of each length.
+    fn ordered_codeword_lengths(&mut self, dimensions: u32, lengths: &[u32]) {
+        assert!(lengths.windows(2).all(|pair| pair[0] <= pair[1]));
+        for b in [0x42, 0x43, 0x56] {
+            self.bits(8, b);
+        }
+        self.bits(16, dimensions);
+        self.bits(24, lengths.len() as u32);
+        self.flag(true); // Ordered
+        self.bits(5, lengths[0] - 1);
+        let mut current_entry = 0;
+        let mut current_length = lengths[0];
+        while current_entry < lengths.len() {
+            let number = lengths.iter().filter(|&&l| l == current_length).count();
+            self.bits(ilog((lengths.len() - current_entry) as u32), number as u32);
+            current_entry += number;
+            current_length += 1;
+        }
+    }
+
+    fn codebook(&mut self, dimensions: u32, lengths: &[u32], lookup: Option<(u32, &[u32])>) {
+        self.codeword_lengths(dimensions, lengths);
+        self.lookup(lookup);
+    }
+
+    fn ordered_codebook(
+        &mut self,
+        dimensions: u32,
+        lengths: &[u32],
+        lookup: Option<(u32, &[u32])>,
+    ) {
+        self.ordered_codeword_lengths(dimensions, lengths);
+        self.lookup(lookup);
+    }
+
+    fn lookup(&mut self, lookup: Option<(u32, &[u32])>) {
+        match lookup {
+            None => self.bits(4, 0),
+            Some((lookup_type, multiplicands)) => {
+                self.bits(4, lookup_type);
+                if lookup_type == 1 {
+                    self.bits(32, float32_pack(-1, 0)); // Minimum value
+                    self.bits(32, float32_pack(1, 0)); // Delta value
+                    self.bits(4, 1); // Value bits - 1
+                    self.flag(false); // Sequence P
+                } else {
+                    self.bits(32, float32_pack(-3, -1));
+                    self.bits(32, float32_pack(1, -2));
+                    self.bits(4, 3);
+                    self.flag(true);
+                }
+                let value_bits = if lookup_type == 1 { 2 } else { 4 };
+                for m in multiplicands {
+                    self.bits(value_bits, *m);
+                }
+            }
+        }
+    }
+
+    fn floor1(&mut self, rangebits: u32, x_values: &[u32]) {
+        self.bits(16, 1);
+        self.bits(5, 3); // Partitions
+        for class in [0, 1, 1] {
+            self.bits(4, class);
+        }
+        // Class 0: two dimensions, one subclass bit chosen by codebook 0
+        self.bits(3, 1);
+        self.bits(2, 1);
+        self.bits(8, 0);
+        self.bits(8, 0); // Subclass 0 has no book
+        self.bits(8, 2); // Subclass 1 uses codebook 1
+
+        // Class 1: three dimensions, no subclasses
+        self.bits(3, 2);
+        self.bits(2, 0);
+        self.bits(8, 2);
+        self.bits(2, 1); // Multiplier - 1
+        self.bits(4, rangebits);
+        assert_eq!(x_values.len(), 8);
+        for x in x_values {
+            self.bits(rangebits, *x);
+        }
+    }
+
+    /// Each value in a vector adds a step of `minimum` to `minimum + 15 *
+    /// delta` to the one before it. Steps near `PI / (order + 1)` spread the
+    /// LSP coefficients evenly, which keeps the floor curve from blowing up.
+    fn floor0_codebook(&mut self, minimum: u32, delta: u32) {
+        self.codeword_lengths(4, &[3; 8]);
+        self.bits(4, 2);
+        self.bits(32, minimum);
+        self.bits(32, delta);
+        self.bits(4, 3); // Value bits - 1
+        self.flag(true); // Sequence P
+        for i in 0..32 {
+            self.bits(4, (i * 5 + 1) % 16);
+        }
+    }
+
+    fn floor0(&mut self, order: u32, bark_map_size: u32, book: u32) {
+        self.bits(16, 0);
+        self.bits(8, order);
+        self.bits(16, 44100); // Rate
+        self.bits(16, bark_map_size);
+        self.bits(6, FLOOR0_AMPLITUDE_BITS);
+        self.bits(8, 40); // Amplitude offset
+        self.bits(4, 1); // Number of books - 1
+        self.bits(8, book);
+        self.bits(8, book);
+    }
+
+    fn residue(&mut self, residue_type: u32, begin: u32, end: u32, partition_size: u32) {
+        self.bits(16, residue_type);
+        self.bits(24, begin);
+        self.bits(24, end);
+        self.bits(24, partition_size - 1);
+        self.bits(6, 2); // Classifications - 1
+        self.bits(8, 2); // Classbook
+
+        // Class 0 is silent, class 1 has one pass, class 2 has two passes
+        self.bits(3, 0);
+        self.flag(false);
+        self.bits(3, 1);
+        self.flag(false);
+        self.bits(3, 3);
+        self.flag(false);
+        self.bits(8, 3);
+        self.bits(8, 4);
+        self.bits(8, 3);
+    }
+
+    fn mapping(&mut self, channels: u8, floor: u32, residue: u32) {
+        self.bits(16, 0);
+        let submaps = if channels > 2 { 2 } else { 1 };
+        self.flag(submaps > 1);
+        if submaps > 1 {
+            self.bits(4, submaps - 1);
+        }
+        self.flag(channels > 1);
+        if channels > 1 {
+            let channel_bits = ilog(channels as u32 - 1);
+            self.bits(8, 0); // One coupling step
+            self.bits(channel_bits, 0);
+            self.bits(channel_bits, 1);
+        }
+        self.bits(2, 0);
+        if submaps > 1 {
+            for channel in 0..channels {
+                self.bits(4, if channel < 2 { 0 } else { 1 });
+            }
+        }
+        for submap in 0..submaps {
+            self.bits(8, 0);
+            self.bits(8, floor);
+            // Multichannel streams use format 2 for the coupled pair
+            let residue = if channels > 1 && submap == 0 {
+                2
+            } else {
+                residue
+            };
+            self.bits(8, residue);
+        }
+    }
+}
+
+/// A standalone codebook with the given codeword lengths and vector lookup
+pub fn codebook(dimensions: u32, lengths: &[u32], lookup: Option<(u32, &[u32])>) -> Vec<u8> {
+    let mut writer = Writer::new();
+    writer.codebook(dimensions, lengths, lookup);
+    writer.finish()
+}
+
+/// Like `codebook`, but with the codeword lengths in ascending order
+pub fn ordered_codebook(
+    dimensions: u32,
+    lengths: &[u32],
+    lookup: Option<(u32, &[u32])>,
+) -> Vec<u8> {
+    let mut writer = Writer::new();
+    writer.ordered_codebook(dimensions, lengths, lookup);
+    writer.finish()
+}
+
+const FLOOR0_AMPLITUDE_BITS: u32 = 6;
+const FLOOR0_ORDERS: [u32; 2] = [10, 17];
+
+pub fn setup_header(channels: u8, floor_type: u16) -> Vec<u8> {
+    let mut w = Writer::new();
+    for b in [5, b'v', b'o', b'r', b'b', b'i', b's'] {
+        w.bits(8, b as u32);
+    }
+
+    // Codebooks
+    w.bits(8, 6);
+    w.codebook(1, &[2, 2, 2, 2], None); // Floor 1 masterbook
+    w.codebook(1, &[4; 16], None); // Floor 1 Y values
+    w.codebook(2, &[4; 16], None); // Residue classbook
+    w.ordered_codebook(2, &[3, 3, 3, 3, 3, 3, 3, 4, 4], Some((1, &[0, 1, 2]))); // Lattice VQ
+    let multiplicands: Vec<u32> = (0..32).map(|i| (i * 7 + 3) % 16).collect();
+    w.codebook(4, &[3; 8], Some((2, &multiplicands))); // Tessellated VQ
+    w.floor0_codebook(float32_pack(1, -2), float32_pack(1, -8)); // Floor 0, order 10
+    w.floor0_codebook(float32_pack(5, -5), float32_pack(1, -9)); // Floor 0, order 17
+
+    // Time domain transforms
+    w.bits(6, 0);
+    w.bits(16, 0);
+
+    // Floors, one for each blocksize
+    w.bits(6, 1);
+    if floor_type == 0 {
+        w.floor0(FLOOR0_ORDERS[0], 64, 5);
+        w.floor0(FLOOR0_ORDERS[1], 256, 6);
+    } else {
+        w.floor1(7, &[5, 60, 17, 90, 40, 3, 110, 9]);
+        w.floor1(10, &[100, 1000, 30, 500, 250, 700, 10, 800]);
+    }
+
+    // Residues
+    w.bits(6, 2);
+    w.residue(0, 0, 128, 16);
+    w.residue(1, 8, 900, 32);
+    w.residue(2, 0, 2000, 32);
+
+    // Mappings
+    w.bits(6, 1);
+    w.mapping(channels, 0, 0);
+    w.mapping(channels, 1, 1);
+
+    // Modes
+    w.bits(6, 1);
+    for (blockflag, mapping) in [(false, 0), (true, 1)] {
+        w.flag(blockflag);
+        w.bits(16, 0);
+        w.bits(16, 0);
+        w.bits(8, mapping);
+    }
+    w.flag(true);
+    w.finish()
+}
+
+pub fn audio_packet(
+    rng: &mut Rng,
+    config: &StreamConfig,
+    previous: bool,
+    current: bool,
+    next: bool,
+) -> Vec<u8> {
+    let mut w = Writer::new();
+    w.flag(false);
+    w.bits(1, current as u32);
+    if current == true {
+        w.flag(previous);
+        w.flag(next);
+    }
+
+    // Random floor 0 data could pick a book that doesn't exist, so lay out
+    // valid floors with random amplitudes and coefficients
+    if config.floor_type == 0 {
+        for _ in 0..config.channels {
+            let amplitude = rng.below(1 << FLOOR0_AMPLITUDE_BITS) as u32;
+            w.bits(FLOOR0_AMPLITUDE_BITS, amplitude);
+            if amplitude > 0 {
+                w.bits(2, rng.below(2) as u32); // Book number, both hold 3 bit codewords
+                let vectors = (FLOOR0_ORDERS[current as usize] + 3) / 4;
+                w.bits(3 * vectors, rng.below(1 << (3 * vectors)) as u32);
+            }
+        }
+    }
+    let random_bits = 16 + rng.below(if current == true { 3000 } else { 600 });
+    for _ in 0..random_bits {
+        w.flag(rng.below(2) == 1);
+    }
+    w.finish()
+}
+
+pub fn ogg_page(
+    header_type_flag: u8,
+    granule_position: u64,
+    serial: u32,
+    sequence_no: u32,
+    segment_table: &[u8],
+    data: &[u8],
+) -> Vec<u8> {
+    let mut page = b"OggS".to_vec();
+    page.push(0);
+    page.push(header_type_flag);
+    page.extend_from_slice(&granule_position.to_le_bytes());
+    page.extend_from_slice(&serial.to_le_bytes());
+    page.extend_from_slice(&sequence_no.to_le_bytes());
+    page.extend_from_slice(&[0; 4]);
+    page.push(segment_table.len() as u8);
+    page.extend_from_slice(segment_table);
+    page.extend_from_slice(data);
+
+    let mut crc32 = CRCu32::create_crc(0x04c11db7, 32, 0, 0, false);
+    crc32.digest(&page);
+    page[22..26].copy_from_slice(&crc32.get_crc().to_le_bytes());
+    page
+}
+
+/// Lay out packets, each with the granule position at its end, into pages of
+/// at most `max_segments` segments. Packets are split across pages as needed.
+pub fn paginate(
+    serial: u32,
+    sequence_no: &mut u32,
+    packets: &[(&[u8], u64)],
+    max_segments: usize,
+    first_page: bool,
+    last_page: bool,
+) -> Vec<u8> {
+    let mut stream = Vec::new();
+    let mut segment_table = Vec::new();
+    let mut data = Vec::new();
+    let mut granule_position = u64::MAX; // No packet finishes on the page
+    let mut continued = false;
+    let mut is_first = first_page;
+    for (i, (packet, packet_granule_position)) in packets.iter().enumerate() {
+        let mut offset = 0;
+        loop {
+            let len = (packet.len() - offset).min(255);
+            segment_table.push(len as u8);
+            data.extend_from_slice(&packet[offset..offset + len]);
+            offset += len;
+            let packet_done = len < 255;
+            if packet_done == true {
+                granule_position = *packet_granule_position;
+            }
+
+            let stream_done = packet_done && i + 1 == packets.len();
+            if segment_table.len() == max_segments || stream_done {
+                let mut flag = 0;
+                if continued == true {
+                    flag |= 0x01;
+                }
+                if is_first == true {
+                    flag |= 0x02;
+                }
+                if stream_done && last_page == true {
+                    flag |= 0x04;
+                }
+                stream.extend(ogg_page(
+                    flag,
+                    granule_position,
+                    serial,
+                    *sequence_no,
+                    &segment_table,
+                    &data,
+                ));
+                *sequence_no += 1;
+                segment_table.clear();
+                data.clear();
+                granule_position = u64::MAX;
+                is_first = false;
+                continued = packet_done == false;
+            }
+            if packet_done == true {
+                break;
+            }
+        }
+    }
+    stream
+}
+
+pub fn header_packets(channels: u8, sample_rate: u32, floor_type: u16) -> [Vec<u8>; 3] {
+    let long_comment = format!("DESCRIPTION={}", "noise ".repeat(60));
+    [
+        id_header(channels, sample_rate),
+        comment_header(
+            "test_stream",
+            &["TITLE=Noise", "ARTIST=Nobody", &long_comment],
+        ),
+        setup_header(channels, floor_type),
+    ]
+}
+
+/// Build a complete stream. Returns the stream along with the audio packets
+/// it contains.
+pub fn build(config: &StreamConfig) -> (Vec<u8>, Vec<Vec<u8>>) {
+    let mut rng = Rng::new(config.seed);
+    let blocks = &config.blocks;
+    let audio_packets: Vec<Vec<u8>> = (0..blocks.len())
+        .map(|i| {
+            let previous = i > 0 && blocks[i - 1];
+            let next = i + 1 < blocks.len() && blocks[i + 1];
+            audio_packet(&mut rng, config, previous, blocks[i], next)
+        })
+        .collect();
+
+    let mut stream = Vec::new();
+    let mut sequence_no = 0;
+
+    // The identification header is alone on the first page. The comment header
+    // is long enough to be split across pages.
+    let [id, comment, setup] =
+        header_packets(config.channels, config.sample_rate, config.floor_type);
+    stream.extend(paginate(
+        config.serial,
+        &mut sequence_no,
+        &[(&id, 0)],
+        255,
+        true,
+        false,
+    ));
+    stream.extend(paginate(
+        config.serial,
+        &mut sequence_no,
+        &[(&comment, 0), (&setup, 0)],
+        1,
+        false,
+        false,
+    ));
+
+    let counts = packet_sample_counts(blocks);
+    let total: u64 = counts.iter().sum();
+    let mut granule_position = config.start_granule;
+    let packets: Vec<(&[u8], u64)> = audio_packets
+        .iter()
+        .zip(counts)
+        .enumerate()
+        .map(|(i, (packet, count))| {
+            granule_position += count;
+            if i + 1 == blocks.len() {
+                granule_position = config.start_granule + total - config.end_trim;
+            }
+            let trimmed_granule_position = granule_position.saturating_sub(config.start_trim);
+            (packet.as_slice(), trimmed_granule_position)
+        })
+        .collect();
+    stream.extend(paginate(
+        config.serial,
+        &mut sequence_no,
+        &packets,
+        config.segments_per_page,
+        false,
+        true,
+    ));
+
+    (stream, audio_packets)
+}
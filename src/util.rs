@@ -1,5 +1,5 @@
 #[rustfmt::skip]
-const FLOOR1_INVERSE_DB_TABLE: [f64; 256] = [
+pub const FLOOR1_INVERSE_DB_TABLE: [f64; 256] = [
     1.0649863e-07, 1.1341951e-07, 1.2079015e-07, 1.2863978e-07,
     1.3699951e-07, 1.4590251e-07, 1.5538408e-07, 1.6548181e-07,
     1.7623575e-07, 1.8768855e-07, 1.9988561e-07, 2.1287530e-07,
@@ -80,6 +80,7 @@ pub fn ilog(x: i32) -> u32 {
 
 /// Translate the packed binary representation of a Vorbis codebook float value
 /// into the representation used by the decoder for floating point numbers.
+/// Large exponents overflow to infinity.
 pub fn float32_unpack(x: u32) -> f32 {
     let mut mantissa: i32 = (x & 0x001FFFFF) as i32;
     let sign: bool = (x & 0x80000000) != 0;
@@ -88,16 +89,20 @@ pub fn float32_unpack(x: u32) -> f32 {
         mantissa *= -1;
     }
     let pow: f32 = 2.0_f32.powi(exponent - 788);
-    let f = mantissa as f32 * pow;
-    assert!(f.is_finite());
-    f
+    mantissa as f32 * pow
 }
 
 /// Find the greatest integer value which to the power of `dimensions` is less
 /// than or equal to `entries` i.e. return_value ** dimensions <= entries.
 pub fn lookup1_values(entries: u32, dimensions: u32) -> u32 {
+    if dimensions == 0 {
+        return 0; // Every value to the 0th power is 1, so there is no greatest one
+    }
     let mut retval: u32 = 0;
-    while (retval + 1).pow(dimensions) <= entries {
+    while (retval + 1)
+        .checked_pow(dimensions)
+        .is_some_and(|value| value <= entries)
+    {
         retval += 1;
     }
     retval
@@ -155,7 +160,7 @@ pub fn render_point(x0: i32, y0: i32, x1: i32, y1: i32, x: i32) -> i32 {
 /// Floor decode type one uses the integer line drawing algorithm of
 /// `render_line(x0, y0, x1, y1, v)` to construct an integer floor curve for
 /// contiguous piecewise line segments.
-pub fn render_line(x0: i32, y0: i32, x1: i32, y1: i32, v: &mut Vec<i32>) {
+pub fn render_line(x0: i32, y0: i32, x1: i32, y1: i32, v: &mut [i32]) {
     assert!(x0 <= x1);
     let range = 0..=v.len();
     assert!(range.contains(&(x0 as usize)));
@@ -183,6 +188,27 @@ pub fn render_line(x0: i32, y0: i32, x1: i32, y1: i32, v: &mut Vec<i32>) {
     }
 }
 
+/// Errors that can tell whether they were caused by running out of data.
+pub trait UnexpectedEof {
+    fn is_unexpected_eof(&self) -> bool;
+}
+
+impl UnexpectedEof for std::io::Error {
+    fn is_unexpected_eof(&self) -> bool {
+        self.kind() == std::io::ErrorKind::UnexpectedEof
+    }
+}
+
+/// Turn running out of data into `None`. Audio packets may end early, in
+/// which case whatever hasn't been read yet is unused or zero.
+pub fn eof_as_none<T, E: UnexpectedEof>(result: Result<T, E>) -> Result<Option<T>, E> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.is_unexpected_eof() == true => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -257,6 +283,8 @@ mod test {
         assert_eq!(lookup1_values(225, 2), 15);
         assert_eq!(lookup1_values(288, 2), 16);
         assert_eq!(lookup1_values(289, 2), 17);
+        assert_eq!(lookup1_values(100, 0), 0);
+        assert_eq!(lookup1_values(0xFFFFFF, 40), 1);
     }
 
     #[test]
@@ -302,6 +330,7 @@ mod test {
         low_neighbor(&[2, 1, 0], 2);
     }
 
+    #[test]
     fn test_high_neighbor() {
         assert_eq!(high_neighbor(&[2, 1, 0], 2), 1);
         assert_eq!(
@@ -393,4 +422,17 @@ mod test {
         let mut v = vec![0; 12];
         render_line(0, 0, -12, 0, &mut v);
     }
+
+    #[test]
+    fn test_eof_as_none() {
+        use std::io::{Error, ErrorKind};
+        assert_eq!(eof_as_none::<_, Error>(Ok(3)).unwrap(), Some(3));
+        let eof: Result<u32, _> = Err(Error::from(ErrorKind::UnexpectedEof));
+        assert_eq!(eof_as_none(eof).unwrap(), None);
+        let other: Result<u32, _> = Err(Error::from(ErrorKind::InvalidData));
+        assert_eq!(
+            eof_as_none(other).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
 }
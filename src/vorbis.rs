@@ -1,8 +1,10 @@
 use crate::{
+    audio::AudioError,
     codebook::{Codebook, CodebookError},
     floor::{Floor, FloorError},
     mapping::{Mapping, MappingError},
     mode::{Mode, ModeError},
+    ogg::OggError,
     residue::{Residue, ResidueError},
     time_domain::{TimeDomainError, TimeDomainTransform},
 };
@@ -11,28 +13,6 @@ use deku::prelude::*;
 use std::io::Cursor;
 use thiserror::Error;
 
-#[derive(Debug, DekuRead)]
-pub struct VorbisPacket {
-    packet_type: u8,
-    #[deku(assert_eq = "*b\"vorbis\"")]
-    magic: [u8; 6],
-    #[deku(ctx = "*packet_type")]
-    pub packet: VorbisPacketType,
-}
-
-#[derive(Debug, DekuRead)]
-#[deku(ctx = "id: u8", id = "id")]
-pub enum VorbisPacketType {
-    #[deku(id = "0")]
-    Audio(Audio),
-    #[deku(id = "1")]
-    Identification(IdHeader),
-    #[deku(id = "3")]
-    Comment(CommentHeader),
-    #[deku(id = "5")]
-    Setup(u8),
-}
-
 #[derive(Debug, DekuRead)]
 pub struct IdHeader {
     #[deku(assert_eq = "0")]
@@ -51,6 +31,23 @@ pub struct IdHeader {
 }
 
 impl IdHeader {
+    /// Decode an identification header packet, checking everything the spec
+    /// requires of it.
+    pub fn from_packet(packet: &[u8]) -> Result<Self, VorbisError> {
+        check_header(packet, 1)?;
+        let ((rest, _), id_header) = Self::from_bytes((&packet[7..], 0))?;
+        if id_header.framing_flag == false {
+            return Err(VorbisError::MissingFramingBit);
+        }
+        if rest.is_empty() == false {
+            return Err(VorbisError::TrailingData(rest.len()));
+        }
+        if id_header.is_valid() == false {
+            return Err(VorbisError::InvalidIdHeader);
+        }
+        Ok(id_header)
+    }
+
     pub fn is_valid(&self) -> bool {
         self.vorbis_version == 0
             && self.audio_channels > 0
@@ -62,16 +59,46 @@ impl IdHeader {
             && self.blocksize_0 <= self.blocksize_1
             && self.framing_flag == true
     }
+
+    pub fn audio_channels(&self) -> u8 {
+        self.audio_channels
+    }
+
+    pub fn audio_sample_rate(&self) -> u32 {
+        self.audio_sample_rate
+    }
+
+    pub fn bitrate_maximum(&self) -> i32 {
+        self.bitrate_maximum
+    }
+
+    pub fn bitrate_nominal(&self) -> i32 {
+        self.bitrate_nominal
+    }
+
+    pub fn bitrate_minimum(&self) -> i32 {
+        self.bitrate_minimum
+    }
+
+    pub fn blocksize_0(&self) -> u16 {
+        self.blocksize_0
+    }
+
+    pub fn blocksize_1(&self) -> u16 {
+        self.blocksize_1
+    }
 }
 
-#[derive(Debug, DekuRead)]
+#[derive(Debug, Clone, DekuRead)]
 pub struct CommentHeader {
+    #[allow(dead_code)] // Only read by deku, for the length of the string
     vendor_length: u32,
     #[deku(
         count = "vendor_length",
         map = "|x: &[u8]| -> Result<_, DekuError> { Ok(String::from_utf8_lossy(x).into_owned()) }"
     )]
     vendor_string: String,
+    #[allow(dead_code)] // Only read by deku, for the number of comments
     user_comment_list_length: u32,
     #[deku(count = "user_comment_list_length")]
     user_comments: Vec<UserComment>,
@@ -80,13 +107,57 @@ pub struct CommentHeader {
 }
 
 impl CommentHeader {
+    /// Decode a comment header packet. Anything after the framing bit is
+    /// allowed, since some taggers leave padding there.
+    pub fn from_packet(packet: &[u8]) -> Result<Self, VorbisError> {
+        check_header(packet, 3)?;
+        check_comment_lengths(&packet[7..])?;
+        let (_, comment_header) = Self::from_bytes((&packet[7..], 0))?;
+        if comment_header.is_valid() == false {
+            return Err(VorbisError::MissingFramingBit);
+        }
+        Ok(comment_header)
+    }
+
     pub fn is_valid(&self) -> bool {
         self.framing_bit == true
     }
+
+    pub fn vendor_string(&self) -> &str {
+        &self.vendor_string
+    }
+
+    pub fn user_comments(&self) -> &[UserComment] {
+        &self.user_comments
+    }
 }
 
-#[derive(Debug, DekuRead)]
+/// Check the vendor string length and the number of comments against what's
+/// left of the packet, since deku allocates for them before reading. Each
+/// comment takes at least the 4 bytes of its length.
+fn check_comment_lengths(data: &[u8]) -> Result<(), VorbisError> {
+    let read_u32 = |at: usize| {
+        data.get(at..at + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    };
+    if let Some(vendor_length) = read_u32(0) {
+        let vendor_end = 4 + vendor_length as u64;
+        if vendor_end > data.len() as u64 {
+            return Err(VorbisError::CommentLengthTooLarge(vendor_length));
+        }
+        let list_start = vendor_end as usize + 4;
+        if let Some(user_comment_list_length) = read_u32(vendor_end as usize) {
+            if user_comment_list_length as u64 * 4 > (data.len() - list_start) as u64 {
+                return Err(VorbisError::CommentLengthTooLarge(user_comment_list_length));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, DekuRead)]
 pub struct UserComment {
+    #[allow(dead_code)] // Only read by deku, for the length of the string
     length: u32,
     #[deku(
         count = "length",
@@ -95,101 +166,154 @@ pub struct UserComment {
     comment: String,
 }
 
+impl UserComment {
+    pub fn comment(&self) -> &str {
+        &self.comment
+    }
+}
+
+#[derive(Debug)]
 pub struct SetupHeader {
-    // TODO: have a Raw struct with all the intermediate data from decoding, that can be moved into a non-raw struct with just the relevant stuff?
-    codebook_count: u8, // TODO: should this be removed? Maybe codebooks.len() is sufficient.
     codebooks: Vec<Codebook>,
-    time_count: u8,
-    time_domain_transforms: Vec<TimeDomainTransform>,
-    floor_count: u8,
     floor_configurations: Vec<Floor>,
-    residue_count: u8,
     residue_configurations: Vec<Residue>,
-    mapping_count: u8,
     mapping_configurations: Vec<Mapping>,
-    mode_count: u8,
     mode_configurations: Vec<Mode>,
     framing_flag: bool,
+    audio_channels: u8, // From the identification header, which the mappings were decoded for
 }
 
 impl SetupHeader {
-    pub fn from_bytes(input: (&[u8], usize)) -> Result<Self, SetupError> {
-        assert_eq!(input.1, 0); // Assume packet starts at bit 0
-        let mut cursor = Cursor::new(input.0);
+    /// Decode a setup header packet. The identification header gives the
+    /// stream's channel count, which the mappings depend on.
+    pub fn from_packet(packet: &[u8], id_header: &IdHeader) -> Result<Self, VorbisError> {
+        check_header(packet, 5)?;
+        let mut cursor = Cursor::new(&packet[7..]);
         let mut reader = BitReader::endian(&mut cursor, LittleEndian);
+        let setup_header = Self::decode(&mut reader, id_header)?;
+        if setup_header.framing_flag == false {
+            return Err(VorbisError::MissingFramingBit);
+        }
+        setup_header.validate()?;
 
-        // This is a hack since currently the vorbis packet's header is not decoded before calling this function
-        let packet_type = reader.read::<u8>(8)?;
-        assert_eq!(packet_type, 5);
-        let magic = [
-            reader.read::<u8>(8)?,
-            reader.read::<u8>(8)?,
-            reader.read::<u8>(8)?,
-            reader.read::<u8>(8)?,
-            reader.read::<u8>(8)?,
-            reader.read::<u8>(8)?,
-        ];
-        assert_eq!(&magic, b"vorbis");
+        // The framing bit is followed by padding to the end of its byte only
+        let trailing = packet.len() - 7 - cursor.position() as usize;
+        if trailing > 0 {
+            return Err(VorbisError::TrailingData(trailing));
+        }
+        Ok(setup_header)
+    }
 
+    /// Decode everything after the packet type and magic, up to and including
+    /// the framing bit.
+    fn decode<R, E>(reader: &mut BitReader<R, E>, id_header: &IdHeader) -> Result<Self, SetupError>
+    where
+        R: std::io::Read,
+        E: bitstream_io::Endianness,
+    {
         // Codebooks
         let codebook_count = reader.read::<u8>(8)? + 1;
         let codebooks = (0..codebook_count)
-            .map(|_| Codebook::decode(&mut reader))
+            .map(|_| Codebook::decode(reader))
             .collect::<Result<_, _>>()?;
 
-        // Time domain transforms
+        // Time domain transforms, which are placeholders in Vorbis I
         let time_count = reader.read::<u8>(6)? + 1;
-        let time_domain_transforms = (0..time_count)
-            .map(|_| TimeDomainTransform::decode(&mut reader))
-            .collect::<Result<_, _>>()?;
+        for _ in 0..time_count {
+            TimeDomainTransform::decode(reader)?;
+        }
 
         // Floors
         let floor_count = reader.read::<u8>(6)? + 1;
         let floor_configurations = (0..floor_count)
-            .map(|_| Floor::decode(&mut reader))
+            .map(|_| Floor::decode(reader))
             .collect::<Result<_, _>>()?;
 
         // Residues
         let residue_count = reader.read::<u8>(6)? + 1;
         let residue_configurations = (0..residue_count)
-            .map(|_| Residue::decode(&mut reader))
+            .map(|_| Residue::decode(reader))
             .collect::<Result<_, _>>()?;
 
         // Mappings
         let mapping_count = reader.read::<u8>(6)? + 1;
         let mapping_configurations = (0..mapping_count)
-            .map(|_| Mapping::decode(&mut reader))
+            .map(|_| Mapping::decode(reader, id_header.audio_channels()))
             .collect::<Result<_, _>>()?;
 
         // Modes
         let mode_count = reader.read::<u8>(6)? + 1;
         let mode_configurations = (0..mode_count)
-            .map(|_| Mode::decode(&mut reader))
+            .map(|_| Mode::decode(reader))
             .collect::<Result<_, _>>()?;
         let framing_flag: bool = reader.read_bit()?;
-        assert!(framing_flag);
-
-        // Check post-conditions since we're not properly handling packet continuation
-        let _ = reader.into_reader(); // Discard the reader
-        let pos = cursor.position();
-        assert_eq!(cursor.into_inner().len(), pos as usize); // Check that cursor made it through the entire underlying buffer - no data left
 
         Ok(Self {
-            codebook_count,
             codebooks,
-            time_count,
-            time_domain_transforms,
-            floor_count,
             floor_configurations,
-            residue_count,
             residue_configurations,
-            mapping_count,
             mapping_configurations,
-            mode_count,
             mode_configurations,
             framing_flag,
+            audio_channels: id_header.audio_channels(),
         })
     }
+
+    /// Check every codebook, floor, residue and mapping number against the
+    /// configurations actually set up, so audio decoding never looks up one
+    /// that doesn't exist.
+    fn validate(&self) -> Result<(), SetupError> {
+        let codebooks = &self.codebooks;
+        for (floor, configuration) in self.floor_configurations.iter().enumerate() {
+            configuration
+                .validate(codebooks)
+                .map_err(|source| SetupError::InvalidFloor { floor, source })?;
+        }
+        for (residue, configuration) in self.residue_configurations.iter().enumerate() {
+            configuration
+                .validate(codebooks)
+                .map_err(|source| SetupError::InvalidResidue { residue, source })?;
+        }
+        let floor_count = self.floor_configurations.len();
+        let residue_count = self.residue_configurations.len();
+        for (mapping, configuration) in self.mapping_configurations.iter().enumerate() {
+            configuration
+                .validate(floor_count, residue_count)
+                .map_err(|source| SetupError::InvalidMapping { mapping, source })?;
+        }
+        let mapping_count = self.mapping_configurations.len();
+        for (mode, configuration) in self.mode_configurations.iter().enumerate() {
+            configuration
+                .validate(mapping_count)
+                .map_err(|source| SetupError::InvalidMode { mode, source })?;
+        }
+        Ok(())
+    }
+
+    pub fn codebooks(&self) -> &[Codebook] {
+        &self.codebooks
+    }
+
+    pub fn floors(&self) -> &[Floor] {
+        &self.floor_configurations
+    }
+
+    pub fn residues(&self) -> &[Residue] {
+        &self.residue_configurations
+    }
+
+    pub fn mappings(&self) -> &[Mapping] {
+        &self.mapping_configurations
+    }
+
+    pub fn modes(&self) -> &[Mode] {
+        &self.mode_configurations
+    }
+
+    /// Number of channels the mappings were decoded for.
+    pub fn audio_channels(&self) -> u8 {
+        self.audio_channels
+    }
 }
 
 #[derive(Debug, Error)]
@@ -212,20 +336,265 @@ pub enum SetupError {
     #[error(transparent)]
     Mode(#[from] ModeError),
 
+    #[error("Floor {floor} is invalid: {source}")]
+    InvalidFloor { floor: usize, source: FloorError },
+
+    #[error("Residue {residue} is invalid: {source}")]
+    InvalidResidue {
+        residue: usize,
+        source: ResidueError,
+    },
+
+    #[error("Mapping {mapping} is invalid: {source}")]
+    InvalidMapping {
+        mapping: usize,
+        source: MappingError,
+    },
+
+    #[error("Mode {mode} is invalid: {source}")]
+    InvalidMode { mode: usize, source: ModeError },
+
     // Represents all cases of `std::io::Error`.
     #[error(transparent)]
     IOError(#[from] std::io::Error),
 }
 
-#[derive(Debug, DekuRead)]
-pub struct Audio {
-    tmp: u8,
+/// Check that a header packet has the expected packet type and the Vorbis
+/// magic that follows it.
+pub fn check_header(packet: &[u8], packet_type: u8) -> Result<(), VorbisError> {
+    match packet.first() {
+        Some(&found) if found != packet_type => Err(VorbisError::WrongPacketType {
+            expected: packet_type,
+            found,
+        }),
+        Some(_) if packet.get(1..7) == Some(b"vorbis") => Ok(()),
+        _ => Err(VorbisError::BadMagic),
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum VorbisError {
+    #[error("Expected packet type {expected}, got {found}")]
+    WrongPacketType { expected: u8, found: u8 },
+
+    #[error("Invalid identification header")]
+    InvalidIdHeader,
+
+    #[error("Comment header length runs past the end of the packet: {0}")]
+    CommentLengthTooLarge(u32),
+
+    #[error("Packet doesn't start with the Vorbis magic")]
+    BadMagic,
+
+    #[error("Header is missing its framing bit")]
+    MissingFramingBit,
+
+    #[error("{0} bytes of unexpected data after the header")]
+    TrailingData(usize),
+
+    #[error("Stream ended before all headers were read")]
+    MissingHeader,
+
+    #[error("More input is needed to carry on decoding")]
+    NeedMoreData,
+
+    #[error("Input fed after it was finished")]
+    InputFinished,
+
+    #[error("Next stream has {channels} channels at {sample_rate} Hz")]
+    FormatChanged { channels: u8, sample_rate: u32 },
+
+    #[error(transparent)]
+    Setup(#[from] SetupError),
+
+    #[error(transparent)]
+    Audio(#[from] AudioError),
+
+    #[error(transparent)]
+    Ogg(#[from] OggError),
+
+    #[error(transparent)]
+    Deku(#[from] DekuError),
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::test_stream;
+
+    fn test_id_header(channels: u8) -> IdHeader {
+        let packet = test_stream::id_header(channels, 44100);
+        IdHeader::from_packet(&packet).unwrap()
+    }
+
+    #[test]
+    fn test_setup_header_channels() {
+        // Stereo, with the two channels coupled
+        let id_header = test_id_header(2);
+        let packet = test_stream::setup_header(2, 1);
+        let setup_header = SetupHeader::from_packet(&packet, &id_header).unwrap();
+        assert_eq!(setup_header.audio_channels(), 2);
+        for mapping in setup_header.mappings() {
+            assert_eq!(mapping.mux(), &[0, 0]);
+            assert_eq!(mapping.coupling_pairs().collect::<Vec<_>>(), vec![(0, 1)]);
+        }
+
+        // 5.1, with the last four channels in a second submap
+        let id_header = test_id_header(6);
+        let packet = test_stream::setup_header(6, 1);
+        let setup_header = SetupHeader::from_packet(&packet, &id_header).unwrap();
+        assert_eq!(setup_header.audio_channels(), 6);
+        assert_eq!(setup_header.mappings().len(), 2);
+        for mapping in setup_header.mappings() {
+            assert_eq!(mapping.mux(), &[0, 0, 1, 1, 1, 1]);
+            assert_eq!(mapping.submaps().len(), 2);
+            assert_eq!(mapping.coupling_pairs().collect::<Vec<_>>(), vec![(0, 1)]);
+        }
+        assert_eq!(setup_header.modes().len(), 2);
+
+        // Channel numbers take no bits in a mono stream, so the stereo setup
+        // header is misread as coupling channel 0 with itself
+        let id_header = test_id_header(1);
+        let packet = test_stream::setup_header(2, 1);
+        assert!(matches!(
+            SetupHeader::from_packet(&packet, &id_header),
+            Err(VorbisError::Setup(SetupError::Mapping(
+                MappingError::PolarAngEqualsMag(0, 0)
+            )))
+        ));
+    }
+
+    #[test]
+    fn test_setup_header_validate() {
+        // The last mode's mapping number is the 8 bits before the framing
+        // bit, which is the highest bit set in the last byte
+        let id_header = test_id_header(2);
+        let mut packet = test_stream::setup_header(2, 1);
+        let last = packet.len() - 1;
+        let framing_bit = last * 8 + 7 - packet[last].leading_zeros() as usize;
+        for (i, bit) in (framing_bit - 8..framing_bit).enumerate() {
+            let value = (5 >> i) & 1;
+            packet[bit / 8] = packet[bit / 8] & !(1 << (bit % 8)) | value << (bit % 8);
+        }
+        let err = SetupHeader::from_packet(&packet, &id_header).unwrap_err();
+        assert!(matches!(
+            err,
+            VorbisError::Setup(SetupError::InvalidMode {
+                mode: 1,
+                source: ModeError::MappingOutOfRange(5)
+            })
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Mode 1 is invalid: Mapping 5 is not configured"
+        );
+    }
 
     #[test]
-    fn test_() {}
+    fn test_header_errors() {
+        let id_packet = test_stream::id_header(2, 44100);
+        let id_header = IdHeader::from_packet(&id_packet).unwrap();
+        let comment_packet = test_stream::comment_header("test", &["A=b"]);
+        let setup_packet = test_stream::setup_header(2, 1);
+
+        // Packet type and magic
+        assert!(matches!(
+            IdHeader::from_packet(&[]),
+            Err(VorbisError::BadMagic)
+        ));
+        assert!(matches!(
+            IdHeader::from_packet(&comment_packet),
+            Err(VorbisError::WrongPacketType {
+                expected: 1,
+                found: 3
+            })
+        ));
+        assert!(matches!(
+            SetupHeader::from_packet(&setup_packet[..4], &id_header),
+            Err(VorbisError::BadMagic)
+        ));
+        let mut packet = comment_packet.clone();
+        packet[1] = b'V';
+        assert!(matches!(
+            CommentHeader::from_packet(&packet),
+            Err(VorbisError::BadMagic)
+        ));
+
+        // Framing bits, with the setup header's in the highest bit set in
+        // its last byte
+        let mut packet = id_packet.clone();
+        *packet.last_mut().unwrap() = 0;
+        assert!(matches!(
+            IdHeader::from_packet(&packet),
+            Err(VorbisError::MissingFramingBit)
+        ));
+        let mut packet = comment_packet.clone();
+        *packet.last_mut().unwrap() = 0;
+        assert!(matches!(
+            CommentHeader::from_packet(&packet),
+            Err(VorbisError::MissingFramingBit)
+        ));
+        let mut packet = setup_packet.clone();
+        let last = packet.last_mut().unwrap();
+        *last &= !(0x80 >> last.leading_zeros());
+        assert!(matches!(
+            SetupHeader::from_packet(&packet, &id_header),
+            Err(VorbisError::MissingFramingBit)
+        ));
+
+        // Trailing data, which only the comment header allows
+        let mut packet = id_packet.clone();
+        packet.push(0);
+        assert!(matches!(
+            IdHeader::from_packet(&packet),
+            Err(VorbisError::TrailingData(1))
+        ));
+        let mut packet = comment_packet.clone();
+        packet.extend_from_slice(&[0; 16]);
+        assert!(CommentHeader::from_packet(&packet).is_ok());
+        let mut packet = setup_packet.clone();
+        packet.extend_from_slice(&[0; 3]);
+        assert!(matches!(
+            SetupHeader::from_packet(&packet, &id_header),
+            Err(VorbisError::TrailingData(3))
+        ));
+
+        // Truncated packets
+        assert!(matches!(
+            IdHeader::from_packet(&id_packet[..20]),
+            Err(VorbisError::Deku(_))
+        ));
+        assert!(matches!(
+            SetupHeader::from_packet(&setup_packet[..setup_packet.len() - 1], &id_header),
+            Err(VorbisError::Setup(SetupError::Mode(ModeError::IOError(_))))
+        ));
+
+        // Lengths in the comment header too large for the packet, which
+        // mustn't be allocated for
+        let mut packet = comment_packet.clone();
+        packet[7..11].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            CommentHeader::from_packet(&packet),
+            Err(VorbisError::CommentLengthTooLarge(u32::MAX))
+        ));
+        let mut packet = comment_packet.clone();
+        packet[15..19].copy_from_slice(&0x7FFF_FFFF_u32.to_le_bytes());
+        assert!(matches!(
+            CommentHeader::from_packet(&packet),
+            Err(VorbisError::CommentLengthTooLarge(0x7FFF_FFFF))
+        ));
+        let mut packet = comment_packet.clone();
+        packet[15..19].copy_from_slice(&3_u32.to_le_bytes());
+        assert!(matches!(
+            CommentHeader::from_packet(&packet),
+            Err(VorbisError::CommentLengthTooLarge(3))
+        ));
+
+        // Invalid fields
+        let packet = test_stream::id_header(0, 44100);
+        assert!(matches!(
+            IdHeader::from_packet(&packet),
+            Err(VorbisError::InvalidIdHeader)
+        ));
+    }
 }
@@ -0,0 +1,196 @@
+use std::f64::consts::FRAC_PI_2;
+
+/// The Vorbis power sine windows for both blocksizes.
+///
+/// A window rises over its left slope, stays at one and falls over its right
+/// slope. Long blocks next to short blocks use the short slope on that side,
+/// centered on the same point, with zeros outside of it and ones inside.
+#[derive(Debug)]
+pub struct Window {
+    blocksizes: [usize; 2],
+    /// The rising slope for each blocksize, half a block long
+    slopes: [Vec<f32>; 2],
+}
+
+impl Window {
+    pub fn new(blocksize_0: usize, blocksize_1: usize) -> Self {
+        let slope = |n: usize| {
+            let len = n / 2;
+            (0..len)
+                .map(|i| {
+                    let x = (i as f64 + 0.5) / len as f64 * FRAC_PI_2;
+                    (FRAC_PI_2 * x.sin().powi(2)).sin() as f32
+                })
+                .collect()
+        };
+
+        Self {
+            blocksizes: [blocksize_0, blocksize_1],
+            slopes: [slope(blocksize_0), slope(blocksize_1)],
+        }
+    }
+
+    /// Window a block of samples. The window flags say whether the previous
+    /// and next blocks are long, and only matter for long blocks.
+    pub fn apply(
+        &self,
+        samples: &mut [f32],
+        blockflag: bool,
+        previous_window_flag: bool,
+        next_window_flag: bool,
+    ) {
+        let n = self.blocksizes[blockflag as usize];
+        assert_eq!(samples.len(), n);
+
+        let left = &self.slopes[(blockflag == true && previous_window_flag == true) as usize];
+        let left_start = n / 4 - left.len() / 2;
+        let left_end = left_start + left.len();
+        let right = &self.slopes[(blockflag == true && next_window_flag == true) as usize];
+        let right_start = n * 3 / 4 - right.len() / 2;
+        let right_end = right_start + right.len();
+
+        samples[..left_start].fill(0.0);
+        for (sample, w) in samples[left_start..left_end].iter_mut().zip(left) {
+            *sample *= w;
+        }
+        for (sample, w) in samples[right_start..right_end]
+            .iter_mut()
+            .zip(right.iter().rev())
+        {
+            *sample *= w;
+        }
+        samples[right_end..].fill(0.0);
+    }
+}
+
+/// Overlap-add of consecutive windowed blocks.
+///
+/// The right half of each block is kept until the next block arrives. The
+/// finished samples then run from the center of the previous block to the
+/// center of the current one, so the first block gives none.
+#[derive(Debug, Default)]
+pub struct OverlapAdd {
+    previous: Option<Vec<Vec<f32>>>,
+}
+
+impl OverlapAdd {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the left half of `blocks`, one per channel, to the right half of
+    /// the previous blocks and return the finished samples for each channel.
+    pub fn push(&mut self, blocks: &[Vec<f32>]) -> Vec<Vec<f32>> {
+        let n = blocks.first().map_or(0, |block| block.len());
+
+        let pcm = match self.previous.take() {
+            Some(previous) => previous
+                .iter()
+                .zip(blocks)
+                .map(|(previous_half, block)| {
+                    // The slopes of both windows are centered a quarter of
+                    // the way into their halves
+                    let previous_n = previous_half.len() * 2;
+                    let len = previous_n / 4 + n / 4;
+                    (0..len)
+                        .map(|i| {
+                            let previous = previous_half.get(i).copied().unwrap_or(0.0);
+                            let current = (i + n / 4)
+                                .checked_sub(previous_n / 4)
+                                .and_then(|j| block[..n / 2].get(j).copied())
+                                .unwrap_or(0.0);
+                            previous + current
+                        })
+                        .collect()
+                })
+                .collect(),
+            None => vec![Vec::new(); blocks.len()],
+        };
+
+        self.previous = Some(blocks.iter().map(|block| block[n / 2..].to_vec()).collect());
+        pcm
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_window_shape() {
+        let window = Window::new(8, 32);
+
+        // Short blocks always use the short slopes
+        let mut samples = vec![1.0; 8];
+        window.apply(&mut samples, false, true, true);
+        assert_eq!(&samples[..4], &window.slopes[0][..]);
+        assert!(samples[4..].iter().eq(window.slopes[0].iter().rev()));
+
+        // Long blocks between long blocks are long slopes all the way
+        let mut samples = vec![1.0; 32];
+        window.apply(&mut samples, true, true, true);
+        assert_eq!(&samples[..16], &window.slopes[1][..]);
+        assert!(samples[16..].iter().eq(window.slopes[1].iter().rev()));
+
+        // Long blocks between short blocks use the short slopes, padded with
+        // zeros on the outside and ones on the inside
+        let mut samples = vec![1.0; 32];
+        window.apply(&mut samples, true, false, false);
+        assert_eq!(&samples[..6], &[0.0; 6]);
+        assert_eq!(&samples[6..10], &window.slopes[0][..]);
+        assert_eq!(&samples[10..22], &[1.0; 12]);
+        assert!(samples[22..26].iter().eq(window.slopes[0].iter().rev()));
+        assert_eq!(&samples[26..], &[0.0; 6]);
+
+        // Different shapes on each side
+        let mut samples = vec![1.0; 32];
+        window.apply(&mut samples, true, false, true);
+        assert_eq!(&samples[..6], &[0.0; 6]);
+        assert_eq!(&samples[6..10], &window.slopes[0][..]);
+        assert_eq!(&samples[10..16], &[1.0; 6]);
+        assert!(samples[16..].iter().eq(window.slopes[1].iter().rev()));
+    }
+
+    #[test]
+    fn test_window_power_complementary() {
+        // Each slope and its mirror image add up to one in power, which is
+        // what lets overlapping blocks cancel out each other's aliasing
+        let window = Window::new(64, 2048);
+        for slope in &window.slopes {
+            for (a, b) in slope.iter().zip(slope.iter().rev()) {
+                assert!((a * a + b * b - 1.0).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn test_overlap_add() {
+        // Blocks of ones windowed twice overlap-add back to exactly ones,
+        // across every combination of long and short blocks
+        let window = Window::new(8, 32);
+        let blockflags = [
+            false, false, true, true, false, true, false, false, true, true,
+        ];
+        let mut overlap_add = OverlapAdd::new();
+        for (i, &blockflag) in blockflags.iter().enumerate() {
+            let previous = i > 0 && blockflags[i - 1];
+            let next = blockflags.get(i + 1).copied().unwrap_or(false);
+            let n = window.blocksizes[blockflag as usize];
+            let mut block = vec![1.0; n];
+            window.apply(&mut block, blockflag, previous, next);
+            window.apply(&mut block, blockflag, previous, next);
+
+            let pcm = overlap_add.push(&[block.clone(), block]);
+            assert_eq!(pcm.len(), 2);
+            if i == 0 {
+                assert_eq!(pcm, vec![Vec::<f32>::new(); 2]);
+                continue;
+            }
+            let previous_n = window.blocksizes[previous as usize];
+            for channel in pcm {
+                assert_eq!(channel.len(), previous_n / 4 + n / 4);
+                assert!(channel.iter().all(|sample| (sample - 1.0).abs() < 1e-6));
+            }
+        }
+    }
+}